@@ -1,25 +1,309 @@
+#![allow(clippy::too_many_arguments, clippy::type_complexity)]
+
 use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::time::{Duration, Instant};
 
-use bevy::core::FixedTimestep;
+use bevy::ecs::schedule::ShouldRun;
+use bevy::ecs::system::SystemParam;
+use bevy::input::InputSystem;
 use bevy::prelude::*;
+use bevy::render::render_resource::{Extent3d, TextureDimension, TextureFormat};
+use bevy::window::WindowId;
+use bevy::winit::WinitWindows;
 use rand::prelude::random;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+use serde::{Deserialize, Serialize};
+use winit::window::Icon;
 
 const ARENA_WIDTH: u32 = 32;
 const ARENA_HEIGHT: u32 = 18;
 
+const WINDOW_TITLE: &str = "Snake!";
 const WINDOW_WIDTH: f32 = 1280.0;
 const WINDOW_HEIGHT: f32 = 800.0;
 
 const SNAKE_HEAD_COLOR: Color = Color::rgb(0.7, 0.7, 0.7);
 const FOOD_COLOR: Color = Color::rgb(1.0, 0.0, 1.0);
+const FREEZE_FOOD_COLOR: Color = Color::rgb(0.6, 0.9, 1.0);
+const SMART_FOOD_COLOR: Color = Color::rgb(1.0, 0.6, 0.0);
 const SNAKE_SEGMENT_COLOR: Color = Color::rgb(0.3, 0.3, 0.3);
+const ICE_COLOR: Color = Color::rgb(0.35, 0.55, 0.7);
+const WALL_COLOR: Color = Color::rgb(0.55, 0.35, 0.2);
+const BACKGROUND_COLOR: Color = Color::rgb(0.04, 0.04, 0.04);
+
+const MOVEMENT_STEP_SECS: f32 = 0.150;
+
+const MIN_STEP_SECS: f32 = 0.03;
+const MAX_STEP_SECS: f32 = 1.0;
+// Frame deltas are multiplied by the game speed, so an unbounded factor could overflow them.
+const MAX_GAME_SPEED: f32 = 100.0;
+const SPEED_UP_KEY: KeyCode = KeyCode::PageUp;
+const SLOW_DOWN_KEY: KeyCode = KeyCode::PageDown;
+const SPEED_STEP_FACTOR: f32 = 0.9;
+// Hues for the slowest and the fastest step when coloring the snake by speed.
+const CALM_HUE: f32 = 240.0;
+const FAST_HUE: f32 = 0.0;
+
+const PAUSE_KEY: KeyCode = KeyCode::P;
+
+const REMAP_KEY: KeyCode = KeyCode::F1;
+const REMAP_CANCEL_KEY: KeyCode = KeyCode::Escape;
+const KEY_BINDINGS_PATH: &str = "keybindings.json";
+
+const SAVE_KEY: KeyCode = KeyCode::F5;
+const LOAD_KEY: KeyCode = KeyCode::F9;
+const SAVE_PATH: &str = "savegame.json";
+
+// The running game is written here every few ticks so it can be resumed after a crash.
+const SESSION_PATH: &str = "session.tmp";
+const SESSION_SAVE_TICKS: u64 = 10;
+const RESUME_KEY: KeyCode = KeyCode::F10;
+
+const HEATMAP_KEY: KeyCode = KeyCode::F6;
+const HEATMAP_PATH: &str = "heatmap.csv";
+
+const GRID_LABEL_KEY: KeyCode = KeyCode::G;
+const GRID_LABEL_COLOR: Color = Color::rgba(1.0, 1.0, 1.0, 0.3);
+const GRID_LABEL_FONT_SIZE: f32 = 14.0;
+
+const PROJECTION_KEY: KeyCode = KeyCode::H;
+const PROJECTION_TICKS: usize = 5;
+const PROJECTION_COLOR: Color = Color::rgba(0.7, 0.7, 0.7, 0.25);
+
+const SPAWN_MARKER_KEY: KeyCode = KeyCode::F3;
+const SPAWN_MARKER_COUNT: usize = 20;
+const SPAWN_MARKER_COLOR: Color = Color::rgb(0.2, 1.0, 0.4);
+
+const TELEPORT_KEY: KeyCode = KeyCode::T;
+const TELEPORT_CHARGES: u32 = 1;
+
+const REVERSE_KEY: KeyCode = KeyCode::R;
+const REVERSE_CHARGES: u32 = 1;
+
+const HUD_FONT_SIZE: f32 = 28.0;
+const HUD_LAYOUT_KEY: KeyCode = KeyCode::F7;
+const HUD_COLOR: Color = Color::rgb(0.8, 0.8, 0.8);
+const SCORE_PULSE_COLOR: Color = Color::rgb(0.9, 0.9, 0.9);
+const SCORE_BEAT_COLOR: Color = Color::WHITE;
+const SCORE_BEAT_TICKS: u64 = 4;
+const SCORE_PULSE_SECS: f32 = 0.1;
+
+const CIRCLE_TEXTURE_SIZE: u32 = 64;
+
+const FONT_PATH: &str = "fonts/DejaVuSans-Bold.ttf";
 
-#[derive(Component, Clone, Copy, PartialEq, Eq)]
+const FOOD_SCORE: u32 = 1;
+const FREEZE_SECS: f32 = 1.0;
+
+const POPUP_COLOR: Color = Color::rgb(1.0, 1.0, 1.0);
+const POPUP_FONT_SIZE: f32 = 24.0;
+const POPUP_SECS: f32 = 0.8;
+const POPUP_RISE_SPEED: f32 = 60.0;
+
+const FOOD_SPAWN_ANIM_SECS: f32 = 0.25;
+
+// With fewer free cells than this outside the cone ahead of the head, biased food placement
+// falls back to picking any free cell.
+const FOOD_BIAS_MIN_CELLS: usize = 8;
+
+const TONGUE_COLOR: Color = Color::rgb(0.9, 0.2, 0.3);
+const TONGUE_INTERVAL_SECS: f32 = 2.5;
+const TONGUE_FLICK_SECS: f32 = 0.3;
+const TONGUE_LENGTH: f32 = 0.4;
+const TONGUE_THICKNESS: f32 = 0.12;
+
+const REWIND_KEY: KeyCode = KeyCode::Back;
+const REWIND_HISTORY_LEN: usize = 30;
+const REWIND_TICKS: usize = 5;
+
+// Hotkeys that can't double as steering keys.
+const RESERVED_KEYS: [KeyCode; 16] = [
+    SPEED_UP_KEY,
+    SLOW_DOWN_KEY,
+    PAUSE_KEY,
+    REMAP_KEY,
+    REMAP_CANCEL_KEY,
+    SAVE_KEY,
+    LOAD_KEY,
+    RESUME_KEY,
+    HEATMAP_KEY,
+    GRID_LABEL_KEY,
+    PROJECTION_KEY,
+    SPAWN_MARKER_KEY,
+    TELEPORT_KEY,
+    REVERSE_KEY,
+    HUD_LAYOUT_KEY,
+    REWIND_KEY,
+];
+
+#[derive(Component, Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
 struct Position {
     x: i32,
     y: i32,
 }
 
+impl Position {
+    fn step(self, direction: Direction) -> Self {
+        match direction {
+            Direction::Left => Self {
+                x: self.x - 1,
+                ..self
+            },
+            Direction::Right => Self {
+                x: self.x + 1,
+                ..self
+            },
+            Direction::Up => Self {
+                y: self.y + 1,
+                ..self
+            },
+            Direction::Down => Self {
+                y: self.y - 1,
+                ..self
+            },
+        }
+    }
+
+    // Whether `other` lies in the 90 degree cone in front of this cell when facing `direction`.
+    fn is_ahead(self, direction: Direction, other: Position) -> bool {
+        let front = self.step(direction);
+        let (fx, fy) = (front.x - self.x, front.y - self.y);
+        let (dx, dy) = (other.x - self.x, other.y - self.y);
+        let forward = dx * fx + dy * fy;
+        let lateral = (dx * fy - dy * fx).abs();
+        forward > 0 && lateral <= forward
+    }
+
+    // The direction that steps from this cell onto `other`, if the two are adjacent.
+    fn direction_to(self, other: Position) -> Option<Direction> {
+        [
+            Direction::Left,
+            Direction::Right,
+            Direction::Up,
+            Direction::Down,
+        ]
+        .into_iter()
+        .find(|direction| self.step(*direction) == other)
+    }
+
+    fn in_arena(&self, arena: &ArenaSize) -> bool {
+        self.x >= 0
+            && self.y >= 0
+            && (self.x as u32) < arena.width
+            && (self.y as u32) < arena.height
+    }
+}
+
+// The playable grid. Rounds start at ARENA_WIDTH x ARENA_HEIGHT; --grow-arena adds to it.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct ArenaSize {
+    width: u32,
+    height: u32,
+}
+
+impl Default for ArenaSize {
+    fn default() -> Self {
+        Self {
+            width: ARENA_WIDTH,
+            height: ARENA_HEIGHT,
+        }
+    }
+}
+
+impl ArenaSize {
+    fn cells(self) -> impl Iterator<Item = Position> {
+        (0..self.width as i32)
+            .flat_map(move |x| (0..self.height as i32).map(move |y| Position { x, y }))
+    }
+
+    fn index(&self, pos: &Position) -> usize {
+        (pos.y as u32 * self.width + pos.x as u32) as usize
+    }
+}
+
+// Grow the arena by a ring's worth of cells at every length milestone.
+struct ArenaGrowth(bool);
+
+// Draw order; position_translation takes the z coordinate of arena entities from this.
+#[derive(Component, Clone, Copy)]
+enum ZLayer {
+    Background,
+    Grid,
+    Wall,
+    Food,
+    Body,
+    Head,
+    Popup,
+    Overlay,
+}
+
+impl ZLayer {
+    fn z(self) -> f32 {
+        self as u8 as f32
+    }
+}
+
+struct Theme {
+    clear: Color,
+    head: Color,
+    body: Color,
+    food: Color,
+    freeze_food: Color,
+    smart_food: Color,
+    background: Color,
+    wall: Color,
+    // Drawn around the snake, food and walls alike; the default theme leaves it off.
+    outline: Outline,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            clear: BACKGROUND_COLOR,
+            head: SNAKE_HEAD_COLOR,
+            body: SNAKE_SEGMENT_COLOR,
+            food: FOOD_COLOR,
+            freeze_food: FREEZE_FOOD_COLOR,
+            smart_food: SMART_FOOD_COLOR,
+            background: ICE_COLOR,
+            wall: WALL_COLOR,
+            outline: Outline::default(),
+        }
+    }
+}
+
+impl Theme {
+    fn high_contrast() -> Self {
+        Self {
+            clear: Color::BLACK,
+            head: Color::WHITE,
+            body: Color::YELLOW,
+            food: Color::CYAN,
+            freeze_food: Color::rgb(0.0, 1.0, 0.0),
+            smart_food: Color::RED,
+            background: Color::rgb(0.0, 0.0, 0.8),
+            wall: Color::rgb(1.0, 0.3, 0.0),
+            outline: Outline {
+                color: Color::FUCHSIA,
+                thickness: 0.3,
+            },
+        }
+    }
+
+    fn color(&self, layer: ZLayer) -> Option<Color> {
+        match layer {
+            ZLayer::Background => Some(self.background),
+            ZLayer::Wall => Some(self.wall),
+            ZLayer::Food => Some(self.food),
+            ZLayer::Body => Some(self.body),
+            ZLayer::Head => Some(self.head),
+            ZLayer::Grid | ZLayer::Popup | ZLayer::Overlay => None,
+        }
+    }
+}
+
 #[derive(Component)]
 struct Size {
     width: f32,
@@ -40,6 +324,63 @@ struct SnakeHead {
     direction: Direction,
 }
 
+#[derive(Component)]
+struct Tongue {
+    timer: Timer,
+}
+
+struct ShowTongue(bool);
+
+#[derive(Clone, Copy)]
+struct Outline {
+    color: Color,
+    // Extra size relative to the segment; 0.0 disables the outline.
+    thickness: f32,
+}
+
+impl Default for Outline {
+    fn default() -> Self {
+        Self {
+            color: Color::rgb(0.1, 0.1, 0.1),
+            thickness: 0.0,
+        }
+    }
+}
+
+struct ReducedMotion(bool);
+
+struct PauseOverlay {
+    color: Color,
+    // An alpha of 0.0 disables the dimming.
+    alpha: f32,
+}
+
+impl Default for PauseOverlay {
+    fn default() -> Self {
+        Self {
+            color: Color::BLACK,
+            alpha: 0.4,
+        }
+    }
+}
+
+#[derive(Component)]
+struct PauseDim;
+
+struct WindowConfig {
+    title: String,
+    icon_path: Option<String>,
+}
+
+impl Default for WindowConfig {
+    fn default() -> Self {
+        Self {
+            title: WINDOW_TITLE.to_string(),
+            icon_path: None,
+        }
+    }
+}
+
 #[derive(Component)]
 struct InputBuffer {
     inputs: VecDeque<Direction>,
@@ -51,336 +392,6174 @@ struct GrowthEvent;
 
 struct FoodEvent;
 
+struct MilestoneEvent(u32);
+
+// Sent after every step the snake survives, with the tick it completed.
+struct TickEvent(u64);
+
+#[derive(Deref, DerefMut)]
+struct Milestones(Vec<u32>);
+
+impl Default for Milestones {
+    fn default() -> Self {
+        Self(vec![10, 25, 50, 100, 200])
+    }
+}
+
 #[derive(Default)]
 struct LastTailPosition(Option<Position>);
 
-#[derive(Component)]
-struct SnakeSegment;
+#[derive(Default, Deref, DerefMut)]
+struct TickCount(u64);
 
+// How many movement ticks the snake holds still at the start of each round.
+struct GraceTicks(u32);
+
+// Grace ticks left in the current round.
 #[derive(Default, Deref, DerefMut)]
-struct SnakeSegments(Vec<Entity>);
+struct StartGrace(u32);
 
-#[derive(Component)]
-struct Food;
+// Keeps the board empty until the grace period is over, so the first target isn't known early.
+#[derive(Default)]
+struct FoodAfterGrace(bool);
 
-#[derive(PartialEq, Copy, Clone)]
-enum Direction {
-    Left,
-    Up,
-    Right,
-    Down,
+struct MovementTimer {
+    timer: Timer,
+    pending_steps: u32,
+    looping: bool,
 }
 
-impl Direction {
-    fn opposite(self) -> Self {
-        match self {
-            Self::Left => Self::Right,
-            Self::Right => Self::Left,
-            Self::Up => Self::Down,
-            Self::Down => Self::Up,
+impl Default for MovementTimer {
+    fn default() -> Self {
+        Self {
+            timer: Timer::from_seconds(MOVEMENT_STEP_SECS, true),
+            pending_steps: 0,
+            looping: false,
         }
     }
 }
 
-#[derive(SystemLabel, Clone, Hash, Debug, Eq, PartialEq)]
-pub enum SnakeMovement {
-    Input,
-    Movement,
-    Eating,
-    Growth,
-}
+// Upper bound on movement steps run in a single frame, so a long hitch doesn't replay a burst
+// of steps at once.
+#[derive(Deref, DerefMut)]
+struct MaxCatchupSteps(u32);
 
-fn setup_camera(mut commands: Commands) {
-    commands.spawn_bundle(OrthographicCameraBundle::new_2d());
+impl Default for MaxCatchupSteps {
+    fn default() -> Self {
+        Self(3)
+    }
 }
 
-fn setup_snake_game(
-    mut commands: Commands,
-    mut food_writer: EventWriter<FoodEvent>,
-    mut segments: ResMut<SnakeSegments>,
-) {
-    *segments = SnakeSegments(vec![
-        commands
-            .spawn_bundle(SpriteBundle {
-                sprite: Sprite {
-                    color: SNAKE_HEAD_COLOR,
-                    ..default()
-                },
-                ..default()
-            })
-            .insert(SnakeHead {
-                direction: Direction::Up,
-            })
-            .insert(SnakeSegment)
-            .insert(Position { x: 3, y: 3 })
-            .insert(Size::square(0.8))
-            .id(),
-        spawn_segment(commands, Position { x: 3, y: 2 }),
-    ]);
-    food_writer.send(FoodEvent);
-}
+// Frames per second to stop at; None leaves the frame rate to vsync.
+#[derive(Default)]
+struct MaxFps(Option<f32>);
 
-fn spawn_segment(mut commands: Commands, position: Position) -> Entity {
-    commands
-        .spawn_bundle(SpriteBundle {
-            sprite: Sprite {
-                color: SNAKE_SEGMENT_COLOR,
-                ..default()
-            },
-            ..default()
-        })
-        .insert(SnakeSegment)
-        .insert(position)
-        .insert(Size::square(0.65))
-        .id()
-}
+// Scales every gameplay timer and animation; 0.5 plays at half speed, 2.0 at double.
+struct GameSpeed(f32);
 
-fn spawn_food(mut commands: Commands, position: Position) {
-    commands
-        .spawn_bundle(SpriteBundle {
-            sprite: Sprite {
-                color: FOOD_COLOR,
-                ..default()
-            },
-            ..default()
-        })
-        .insert(Food)
-        .insert(position)
-        .insert(Size::square(0.8));
+impl Default for GameSpeed {
+    fn default() -> Self {
+        Self(1.0)
+    }
 }
 
-fn init_inputs(mut commands: Commands) {
-    commands.spawn().insert(InputBuffer {
-        inputs: VecDeque::with_capacity(10),
-    });
+impl GameSpeed {
+    fn scale(&self, delta: Duration) -> Duration {
+        delta.mul_f32(self.0)
+    }
 }
 
-fn snake_movement(
-    mut last_tail_position: ResMut<LastTailPosition>,
-    mut game_over_write: EventWriter<GameOverEvent>,
-    mut food_writer: EventWriter<FoodEvent>,
-    segments: ResMut<SnakeSegments>,
-    mut heads: Query<(Entity, &mut SnakeHead)>,
-    mut position: Query<&mut Position>,
-    mut inputs: Query<&mut InputBuffer>,
-) {
-    if let Some((head_entity, mut head)) = heads.iter_mut().next() {
-        let segment_positions = segments
-            .iter()
-            .map(|e| *position.get_mut(*e).unwrap())
-            .collect::<Vec<Position>>();
-        let mut head_pos = position.get_mut(head_entity).unwrap();
+// Frame time scaled by the game speed, for movement and animations alike. Stands still
+// whenever the game isn't being played.
+#[derive(Default)]
+struct GameClock {
+    delta: Duration,
+}
 
-        if let Some(mut input_buffer) = inputs.iter_mut().next() {
-            while let Some(input) = input_buffer.inputs.pop_front() {
-                if input.opposite() != head.direction && input != head.direction {
-                    head.direction = input;
-                    break;
-                }
-            }
-        }
+#[derive(Default, Deref, DerefMut)]
+struct Score(u32);
 
-        match &head.direction {
-            Direction::Left => {
-                head_pos.x -= 1;
-            }
-            Direction::Right => {
-                head_pos.x += 1;
-            }
-            Direction::Up => {
-                head_pos.y += 1;
-            }
-            Direction::Down => {
-                head_pos.y -= 1;
-            }
-        }
+// Points awarded for every movement tick survived, on top of food.
+#[derive(Default)]
+struct SurvivalScorePerTick(u32);
 
-        if head_pos.x < 0
-            || head_pos.y < 0
-            || head_pos.x as u32 >= ARENA_WIDTH
-            || head_pos.y as u32 >= ARENA_HEIGHT
-        {
-            game_over_write.send(GameOverEvent);
-            food_writer.send(FoodEvent);
-        }
+struct UiFont(Handle<Font>);
 
-        if segment_positions.contains(&head_pos) {
-            game_over_write.send(GameOverEvent);
-            food_writer.send(FoodEvent);
-        }
+#[derive(Deref, DerefMut)]
+struct TeleportCharges(u32);
 
-        segment_positions
-            .iter()
-            .zip(segments.iter().skip(1))
-            .for_each(|(pos, segment)| {
-                *position.get_mut(*segment).unwrap() = *pos;
-            });
-        *last_tail_position = LastTailPosition(Some(*segment_positions.last().unwrap()));
+impl Default for TeleportCharges {
+    fn default() -> Self {
+        Self(TELEPORT_CHARGES)
     }
 }
 
-fn snake_movement_input(keyboard_input: Res<Input<KeyCode>>, mut inputs: Query<&mut InputBuffer>) {
-    if let Some(mut input_buffer) = inputs.iter_mut().next() {
-        if input_buffer.inputs.len() < 3 {
-            if keyboard_input.just_pressed(KeyCode::Left) {
-                input_buffer.inputs.push_back(Direction::Left)
-            } else if keyboard_input.just_pressed(KeyCode::Down) {
-                input_buffer.inputs.push_back(Direction::Down);
-            } else if keyboard_input.just_pressed(KeyCode::Up) {
-                input_buffer.inputs.push_back(Direction::Up);
-            } else if keyboard_input.just_pressed(KeyCode::Right) {
-                input_buffer.inputs.push_back(Direction::Right);
-            }
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+enum HudLayout {
+    #[default]
+    Stacked,
+    Compact,
+    Hidden,
+}
+
+impl HudLayout {
+    fn next(self) -> Self {
+        match self {
+            HudLayout::Stacked => HudLayout::Compact,
+            HudLayout::Compact => HudLayout::Hidden,
+            HudLayout::Hidden => HudLayout::Stacked,
         }
     }
 }
 
-fn game_over(
-    mut commands: Commands,
-    mut reader: EventReader<GameOverEvent>,
-    food_writer: EventWriter<FoodEvent>,
-    segments_res: ResMut<SnakeSegments>,
-    mut inputs: Query<&mut InputBuffer>,
-    food: Query<Entity, With<Food>>,
-    segments: Query<Entity, With<SnakeSegment>>,
-) {
-    if reader.iter().next().is_some() {
-        for ent in food.iter().chain(segments.iter()) {
-            commands.entity(ent).despawn();
-        }
+// The node every HUD text is a child of; HudLayout arranges its children.
+#[derive(Component)]
+struct HudRoot;
 
-        setup_snake_game(commands, food_writer, segments_res);
+#[derive(Component)]
+struct TeleportText;
 
-        if let Some(mut input_buffer) = inputs.iter_mut().next() {
-            input_buffer.inputs.clear();
-        }
+#[derive(Deref, DerefMut)]
+struct ReverseCharges(u32);
+
+impl Default for ReverseCharges {
+    fn default() -> Self {
+        Self(REVERSE_CHARGES)
     }
 }
 
-fn snake_eating(
-    mut commands: Commands,
-    mut growth_writer: EventWriter<GrowthEvent>,
-    mut food_writer: EventWriter<FoodEvent>,
-    food_positions: Query<(Entity, &Position), With<Food>>,
-    head_positions: Query<&Position, With<SnakeHead>>,
-) {
-    for head_pos in head_positions.iter() {
-        for (ent, food_pos) in food_positions.iter() {
-            if food_pos == head_pos {
-                commands.entity(ent).despawn();
-                growth_writer.send(GrowthEvent);
-                food_writer.send(FoodEvent);
-            }
+#[derive(Default)]
+struct Speedrun {
+    target: Option<u32>,
+    // Only food counts toward the target, not points from --tick-score.
+    points: u32,
+    elapsed: f32,
+    running: bool,
+    finished: bool,
+}
+
+impl Speedrun {
+    fn reset(&mut self) {
+        *self = Self {
+            target: self.target,
+            ..default()
+        };
+    }
+}
+
+#[derive(Component)]
+struct SpeedrunText;
+
+#[derive(Component)]
+struct PaceText;
+
+#[derive(Component)]
+struct ResumeText;
+
+// A session left behind by a run that never reached game over, found at startup.
+#[derive(Default)]
+struct PendingSession(Option<SaveGame>);
+
+// Where the running game is autosaved; SESSION_PATH outside of tests.
+struct SessionFile(String);
+
+#[derive(Default)]
+struct SpeedColors(bool);
+
+#[derive(Component)]
+struct ScoreText {
+    pulse: Timer,
+    flash: Color,
+}
+
+// Head visits per cell, indexed by ArenaSize::index.
+#[derive(Deref, DerefMut)]
+struct Heatmap(Vec<u32>);
+
+impl Default for Heatmap {
+    fn default() -> Self {
+        Self::new(&ArenaSize::default())
+    }
+}
+
+impl Heatmap {
+    fn new(arena: &ArenaSize) -> Self {
+        Self(vec![0; (arena.width * arena.height) as usize])
+    }
+
+    // Cells in both sizes keep their counts.
+    fn resize(&mut self, from: &ArenaSize, to: &ArenaSize) {
+        let mut resized = Self::new(to);
+        for pos in from.cells().filter(|pos| pos.in_arena(to)) {
+            resized[to.index(&pos)] = self[from.index(&pos)];
         }
+        *self = resized;
     }
 }
 
-fn snake_growth(
-    commands: Commands,
-    last_tail_position: Res<LastTailPosition>,
-    mut segments: ResMut<SnakeSegments>,
-    mut growth_reader: EventReader<GrowthEvent>,
-) {
-    if growth_reader.iter().next().is_some() {
-        segments.push(spawn_segment(commands, last_tail_position.0.unwrap()));
+// Whether the heatmap starts over with every run instead of covering the whole session.
+struct HeatmapPerRun(bool);
+
+#[derive(Component)]
+struct GridLabel;
+
+struct ShowProjection(bool);
+
+// Enables debugging aids such as the food spawn markers.
+struct DebugMode(bool);
+
+// The most recent food spawn cells, oldest first.
+#[derive(Default, Deref, DerefMut)]
+struct RecentFoodSpawns(VecDeque<Position>);
+
+#[derive(Component)]
+struct SpawnMarker(usize);
+
+// One marker per projected tick, in order.
+#[derive(Component)]
+struct ProjectionGhost(usize);
+
+#[derive(Component)]
+struct FloatingText {
+    timer: Timer,
+    velocity: Vec3,
+}
+
+#[derive(Component)]
+struct SnakeSegment;
+
+// The tail shrinks whenever this runs out before the snake eats; None turns hunger off.
+#[derive(Default)]
+struct Hunger(Option<Timer>);
+
+#[derive(Default, Deref, DerefMut)]
+struct SnakeSegments(Vec<Entity>);
+
+// Draws only every n-th body segment for a dotted look; the hidden ones still collide.
+#[derive(Deref)]
+struct RenderEvery(usize);
+
+impl Default for RenderEvery {
+    fn default() -> Self {
+        Self(1)
     }
 }
 
-fn size_scaling(windows: Res<Windows>, mut query: Query<(&Size, &mut Transform)>) {
-    let window = windows.get_primary().unwrap();
-    for (sprite_size, mut transform) in query.iter_mut() {
-        transform.scale = Vec3::new(
-            sprite_size.width / ARENA_WIDTH as f32 * window.width() as f32,
-            sprite_size.height / ARENA_HEIGHT as f32 * window.height() as f32,
-            1.0,
-        );
+// Gap around the head, body and food, as a fraction of a cell. Unset keeps each sprite's own
+// Size.
+#[derive(Default)]
+struct CellPadding(Option<f32>);
+
+#[derive(Component)]
+struct Food;
+
+#[derive(Component)]
+struct IceTile;
+
+#[derive(Component)]
+struct Wall;
+
+// A ring of walls around the arena edge, kept across rounds; a thickness of 0 leaves it open.
+#[derive(Default)]
+struct BorderWall {
+    thickness: u32,
+}
+
+// Leaves the snake, which starts just inside the border, a few tiles to move up into.
+const MAX_BORDER_THICKNESS: u32 = 6;
+
+impl BorderWall {
+    // Where a round starts, moved inward so a thick border doesn't cover the snake.
+    fn start(&self) -> [Position; 2] {
+        let inset = self.thickness as i32;
+        [
+            Position {
+                x: 3 + inset,
+                y: 3 + inset,
+            },
+            Position {
+                x: 3 + inset,
+                y: 2 + inset,
+            },
+        ]
+    }
+
+    fn covers(&self, arena: &ArenaSize, pos: &Position) -> bool {
+        let edge_distance = pos
+            .x
+            .min(pos.y)
+            .min(arena.width as i32 - 1 - pos.x)
+            .min(arena.height as i32 - 1 - pos.y);
+        edge_distance < self.thickness as i32
+    }
+
+    // The first snake or food cell that a snapshot puts on the border.
+    fn first_covered(&self, arena: &ArenaSize, snapshot: &GameSnapshot) -> Option<Position> {
+        snapshot
+            .segments
+            .iter()
+            .chain(snapshot.food.iter().map(|food| &food.position))
+            .find(|pos| self.covers(arena, pos))
+            .copied()
     }
 }
 
-fn position_translation(windows: Res<Windows>, mut query: Query<(&Position, &mut Transform)>) {
-    fn convert(pos: f32, bound_window: f32, bound_game: f32) -> f32 {
-        let tile_size = bound_window / bound_game;
-        pos / bound_game * bound_window - (bound_window / 2.0) + (tile_size / 2.0)
+#[derive(Component)]
+struct BorderTile;
+
+// Walls added to the board for every food eaten; 0 turns the survival mode off.
+#[derive(Default)]
+struct WallsPerFood(u32);
+
+// Fraction of the arena covered with ice, from 0.0 (none) to 1.0.
+#[derive(Default)]
+struct IceCoverage(f32);
+
+#[derive(Default, Deref, DerefMut)]
+struct FoodSequence(VecDeque<Position>);
+
+// Where food_spawner gets its randomness, so a seeded source can stand in for the thread RNG.
+trait RandomSource: Send + Sync {
+    // `free` is never empty.
+    fn next_free_cell(&mut self, free: &[Position]) -> Position;
+    // Uniform in 0.0..1.0, for the food kind chances.
+    fn roll(&mut self) -> f32;
+    // Only a seeded source can be picked up again where it left off.
+    fn seed_state(&self) -> Option<SeedState> {
+        None
     }
-    let window = windows.get_primary().unwrap();
-    for (pos, mut transform) in query.iter_mut() {
-        transform.translation = Vec3::new(
-            convert(pos.x as f32, window.width() as f32, ARENA_WIDTH as f32),
-            convert(pos.y as f32, window.height() as f32, ARENA_HEIGHT as f32),
-            0.0,
-        );
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+struct SeedState {
+    seed: u64,
+    word_pos: u128,
+}
+
+struct ThreadRandom;
+
+impl RandomSource for ThreadRandom {
+    fn next_free_cell(&mut self, free: &[Position]) -> Position {
+        free[(random::<f32>() * free.len() as f32) as usize]
+    }
+
+    fn roll(&mut self) -> f32 {
+        random()
     }
 }
 
-fn food_spawner(
-    commands: Commands,
-    query: Query<&Position, With<SnakeSegment>>,
-    mut food_reader: EventReader<FoodEvent>,
-) {
-    if food_reader.iter().next().is_some() {
-        let mut new_position = Position {
-            x: (random::<f32>() * ARENA_WIDTH as f32) as i32,
-            y: (random::<f32>() * ARENA_HEIGHT as f32) as i32,
+// The same generator as StdRng, which can't report how far along its stream it is.
+struct SeededRandom {
+    seed: u64,
+    rng: ChaCha12Rng,
+}
+
+impl SeededRandom {
+    fn new(seed: u64) -> Self {
+        Self::resume(SeedState { seed, word_pos: 0 })
+    }
+
+    fn resume(state: SeedState) -> Self {
+        let mut rng = ChaCha12Rng::seed_from_u64(state.seed);
+        rng.set_word_pos(state.word_pos);
+        Self {
+            seed: state.seed,
+            rng,
+        }
+    }
+}
+
+impl RandomSource for SeededRandom {
+    fn next_free_cell(&mut self, free: &[Position]) -> Position {
+        free[self.rng.gen_range(0..free.len())]
+    }
+
+    fn roll(&mut self) -> f32 {
+        self.rng.gen()
+    }
+
+    fn seed_state(&self) -> Option<SeedState> {
+        Some(SeedState {
+            seed: self.seed,
+            word_pos: self.rng.get_word_pos(),
+        })
+    }
+}
+
+struct FoodRandom(Box<dyn RandomSource>);
+
+impl Default for FoodRandom {
+    fn default() -> Self {
+        Self(Box::new(ThreadRandom))
+    }
+}
+
+// Where the very first food goes; cleared once food_spawner has used it.
+#[derive(Default)]
+struct InitialFood(Option<Position>);
+
+// Keeps the first food of every round within this many steps of the head.
+#[derive(Default)]
+struct FirstFoodRadius(Option<u32>);
+
+// Set at the start of each round until food_spawner has placed its first food.
+struct FirstFood(bool);
+
+#[derive(Component)]
+struct FoodLifetime(Timer);
+
+#[derive(Component)]
+struct SpawnAnim {
+    timer: Timer,
+}
+
+// Eating this food holds the snake still for FREEZE_SECS.
+#[derive(Component)]
+struct FreezeFood;
+
+// Chance that a newly placed food is freeze food.
+#[derive(Default)]
+struct FreezeFoodChance(f32);
+
+// Food that runs away from the head, one cell per tick, until it's out of moves.
+#[derive(Component)]
+struct SmartFood {
+    moves_left: u32,
+}
+
+struct SmartFoodConfig {
+    chance: f32,
+    moves: u32,
+}
+
+impl Default for SmartFoodConfig {
+    fn default() -> Self {
+        Self {
+            chance: 0.0,
+            moves: 20,
+        }
+    }
+}
+
+// Set while the snake is frozen; the rest of the game keeps running.
+#[derive(Default)]
+struct MovementFreeze(Option<Timer>);
+
+#[derive(Component)]
+struct FreezeText;
+
+#[derive(Default)]
+struct FoodTimeout(Option<f32>);
+
+#[derive(Deref, DerefMut)]
+struct FoodCount(usize);
+
+impl Default for FoodCount {
+    fn default() -> Self {
+        Self(1)
+    }
+}
+
+// Which special marker a piece of food carried, so a snapshot can bring it back as it was.
+#[derive(Clone, Copy, Default, PartialEq, Debug, Serialize, Deserialize)]
+enum FoodKind {
+    #[default]
+    Plain,
+    Freeze,
+    Smart {
+        moves_left: u32,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+struct SavedFood {
+    #[serde(flatten)]
+    position: Position,
+    // Older saves list bare positions, so their food loads as plain food.
+    #[serde(default)]
+    kind: FoodKind,
+}
+
+impl SavedFood {
+    fn new(position: Position, freeze: Option<&FreezeFood>, smart: Option<&SmartFood>) -> Self {
+        let kind = match (freeze, smart) {
+            (Some(_), _) => FoodKind::Freeze,
+            (None, Some(smart)) => FoodKind::Smart {
+                moves_left: smart.moves_left,
+            },
+            (None, None) => FoodKind::Plain,
         };
+        Self { position, kind }
+    }
+}
 
-        let mut collision = false;
+#[derive(Clone, Serialize, Deserialize)]
+struct GameSnapshot {
+    segments: Vec<Position>,
+    direction: Direction,
+    food: Vec<SavedFood>,
+    // Walls placed during the round; the border is rebuilt from --border instead.
+    #[serde(default)]
+    walls: Vec<Position>,
+}
 
-        loop {
-            for pos in query.iter() {
-                if new_position == *pos {
-                    collision = true;
-                    break;
-                }
+impl GameSnapshot {
+    // Stricter than a save's check, since a hand-written scenario is easy to get wrong.
+    fn validate_scenario(&self, arena: &ArenaSize, border: &BorderWall) -> Result<(), String> {
+        if self.segments.is_empty() {
+            return Err("the snake has no segments".to_string());
+        }
+        if let Some(pos) = self
+            .segments
+            .iter()
+            .chain(self.food.iter().map(|food| &food.position))
+            .chain(self.walls.iter())
+            .find(|pos| !pos.in_arena(arena))
+        {
+            return Err(format!("{:?} is outside the arena", pos));
+        }
+        if let Some(pos) = border.first_covered(arena, self) {
+            return Err(format!("{:?} is under the border", pos));
+        }
+        for pair in self.segments.windows(2) {
+            if (pair[0].x - pair[1].x).abs() + (pair[0].y - pair[1].y).abs() != 1 {
+                return Err(format!("{:?} and {:?} are not adjacent", pair[0], pair[1]));
+            }
+        }
+        for (i, pos) in self.segments.iter().enumerate() {
+            if self.segments[..i].contains(pos)
+                || self.food.iter().any(|food| food.position == *pos)
+                || self.walls.contains(pos)
+            {
+                return Err(format!("{:?} is taken twice", pos));
             }
+        }
+        Ok(())
+    }
+}
 
-            if !collision {
-                break;
-            } else {
-                new_position = Position {
-                    x: (random::<f32>() * ARENA_WIDTH as f32) as i32,
-                    y: (random::<f32>() * ARENA_HEIGHT as f32) as i32,
-                };
-                collision = false;
+// A fixed board to start every round from instead of the usual two segments.
+#[derive(Default)]
+struct Scenario(Option<GameSnapshot>);
+
+struct ScenarioFile(Option<String>);
+
+#[derive(Default, Deref, DerefMut)]
+struct RewindHistory(VecDeque<GameSnapshot>);
+
+// The settings a round was saved under, put back on load so it plays on the same way.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+struct SaveConfig {
+    food_bias: FoodBias,
+    food_count: usize,
+    tick_score: u32,
+    walls_per_food: u32,
+    border: u32,
+    game_speed: f32,
+    step_secs: f32,
+    // Where a --food-seed source had got to, so food keeps turning up in the same order.
+    #[serde(default)]
+    food_seed: Option<SeedState>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SaveGame {
+    arena_width: u32,
+    arena_height: u32,
+    snapshot: GameSnapshot,
+    score: u32,
+    teleport_charges: u32,
+    // Missing from older saves, which then play on under the current settings.
+    #[serde(default)]
+    config: Option<SaveConfig>,
+}
+
+impl SaveGame {
+    fn read(path: &str, arena: &ArenaSize, border: &BorderWall) -> Result<Self, String> {
+        std::fs::read_to_string(path)
+            .map_err(|err| err.to_string())
+            .and_then(|json| serde_json::from_str::<SaveGame>(&json).map_err(|err| err.to_string()))
+            .and_then(|save| save.validate(arena, border).map(|()| save))
+    }
+
+    fn write(&self, path: &str) -> Result<(), String> {
+        serde_json::to_string_pretty(self)
+            .map_err(|err| err.to_string())
+            .and_then(|json| std::fs::write(path, json).map_err(|err| err.to_string()))
+    }
+
+    // Loading never resizes the arena, so the save has to fit the one being played. `border` is
+    // the live one, which a save's own config replaces on load.
+    fn validate(&self, arena: &ArenaSize, border: &BorderWall) -> Result<(), String> {
+        if self.arena_width > arena.width || self.arena_height > arena.height {
+            return Err(format!(
+                "saved for a {}x{} arena, larger than this one's {}x{}",
+                self.arena_width, self.arena_height, arena.width, arena.height
+            ));
+        }
+        if let Some(config) = &self.config {
+            if config.border > MAX_BORDER_THICKNESS {
+                return Err(format!("a {}-tile border is too thick", config.border));
             }
+            if !(config.game_speed > 0.0 && config.game_speed <= MAX_GAME_SPEED) {
+                return Err(format!("{} is not a valid game speed", config.game_speed));
+            }
+            if !(MIN_STEP_SECS..=MAX_STEP_SECS).contains(&config.step_secs) {
+                return Err(format!("{}s is not a valid step", config.step_secs));
+            }
+        }
+        if self.snapshot.segments.is_empty() {
+            return Err("the snake has no segments".to_string());
+        }
+        let mut cells = self
+            .snapshot
+            .segments
+            .iter()
+            .chain(self.snapshot.food.iter().map(|food| &food.position))
+            .chain(self.snapshot.walls.iter());
+        if let Some(pos) = cells.find(|pos| !pos.in_arena(arena)) {
+            return Err(format!("{:?} is outside the arena", pos));
+        }
+        let thickness = self.config.map_or(border.thickness, |config| config.border);
+        let border = BorderWall { thickness };
+        if let Some(pos) = border.first_covered(arena, &self.snapshot) {
+            return Err(format!("{:?} is under the border", pos));
         }
+        Ok(())
+    }
+}
 
-        spawn_food(commands, new_position);
+#[derive(PartialEq, Copy, Clone, Debug, Serialize, Deserialize)]
+enum Direction {
+    Left,
+    Up,
+    Right,
+    Down,
+}
+
+impl Direction {
+    fn opposite(self) -> Self {
+        match self {
+            Self::Left => Self::Right,
+            Self::Right => Self::Left,
+            Self::Up => Self::Down,
+            Self::Down => Self::Up,
+        }
     }
 }
 
-fn main() {
-    App::new()
-        .insert_resource(ClearColor(Color::rgb(0.04, 0.04, 0.04)))
-        .insert_resource(WindowDescriptor {
-            title: "Snake!".to_string(),
-            width: WINDOW_WIDTH,
-            height: WINDOW_HEIGHT,
-            ..default()
-        })
-        .add_startup_system(setup_camera)
-        .add_startup_system(setup_snake_game)
-        .add_startup_system(init_inputs)
-        .insert_resource(SnakeSegments::default())
-        .insert_resource(LastTailPosition::default())
-        .add_event::<GrowthEvent>()
-        .add_system(snake_movement_input.before(snake_movement))
-        .add_event::<GameOverEvent>()
-        .add_event::<FoodEvent>()
-        .add_system_set(
-            SystemSet::new()
-                .with_run_criteria(FixedTimestep::step(0.150))
-                .with_system(snake_movement)
-                .with_system(snake_eating.after(snake_movement))
-                .with_system(snake_growth.after(snake_eating)),
-        )
-        .add_system(game_over.after(snake_movement))
-        .add_system(food_spawner)
-        .add_system_set_to_stage(
-            CoreStage::PostUpdate,
-            SystemSet::new()
-                .with_system(position_translation)
-                .with_system(size_scaling),
-        )
-        .add_plugins(DefaultPlugins)
-        .run();
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+enum SpriteStyle {
+    #[default]
+    Square,
+    Circle,
+}
+
+struct CircleTexture(Handle<Image>);
+
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+enum ViewMode {
+    #[default]
+    Fixed,
+    Scrolling,
+}
+
+// Unlike ViewMode::Scrolling, FollowHead leaves the arena where it is and moves the camera.
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+enum CameraMode {
+    #[default]
+    ArenaCenter,
+    FollowHead,
+}
+
+#[derive(Component)]
+struct MainCamera;
+
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+enum GrowthAnchor {
+    #[default]
+    Tail,
+    Head,
+}
+
+#[derive(Default)]
+struct TurnSmoothing(bool);
+
+#[derive(Default)]
+struct CoalesceInputs(bool);
+
+// Whether running into the neck (the segment right behind the head) is forgiven. Normal steering
+// can't reach it, so this only matters if some movement mode ever lets the head double back.
+#[derive(Default)]
+struct ExemptNeck(bool);
+
+// Hitting a wall or the arena edge bounces the head back and costs the rear half of the body
+// instead of the round; only a lone head still dies.
+#[derive(Default)]
+struct ShedOnWall(bool);
+
+// A snake that is down to its head has no body to turn back into, so it may reverse.
+#[derive(Default)]
+struct LoneHeadReverse(bool);
+
+// Pressing the key for the current heading moves the snake one extra step right away.
+#[derive(Default)]
+struct TurboTap(bool);
+
+// Both ends of the body are heads. They take turns moving, one per tick, and the body grows in
+// the middle.
+#[derive(Default)]
+struct TwoHeaded(bool);
+
+// Only place food the head can currently get to. Costs a flood fill per spawn.
+#[derive(Default)]
+struct ReachableFood(bool);
+
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+enum FoodBias {
+    #[default]
+    None,
+    AvoidAhead,
+}
+
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+enum BufferMode {
+    #[default]
+    Buffered,
+    Immediate,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+enum GameState {
+    Playing,
+    Paused,
+    Remapping,
+}
+
+#[derive(Deref, DerefMut, Serialize, Deserialize)]
+struct KeyBindings(Vec<(Direction, KeyCode)>);
+
+impl KeyBindings {
+    fn read(path: &str) -> Result<Self, String> {
+        std::fs::read_to_string(path)
+            .map_err(|err| err.to_string())
+            .and_then(|json| {
+                serde_json::from_str::<KeyBindings>(&json).map_err(|err| err.to_string())
+            })
+            .and_then(|bindings| bindings.validate().map(|()| bindings))
+    }
+
+    fn write(&self, path: &str) -> Result<(), String> {
+        serde_json::to_string_pretty(self)
+            .map_err(|err| err.to_string())
+            .and_then(|json| std::fs::write(path, json).map_err(|err| err.to_string()))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let directions = [
+            Direction::Left,
+            Direction::Up,
+            Direction::Right,
+            Direction::Down,
+        ];
+        if self.len() != directions.len() {
+            return Err(format!("expected {} bindings", directions.len()));
+        }
+        if let Some(direction) = directions
+            .iter()
+            .find(|direction| !self.iter().any(|(bound, _)| bound == *direction))
+        {
+            return Err(format!("{:?} has no key", direction));
+        }
+        for (i, (_, key)) in self.iter().enumerate() {
+            if RESERVED_KEYS.contains(key) {
+                return Err(format!("{:?} is already a hotkey", key));
+            }
+            if self[..i].iter().any(|(_, other)| other == key) {
+                return Err(format!("{:?} is bound twice", key));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        Self(vec![
+            (Direction::Left, KeyCode::Left),
+            (Direction::Down, KeyCode::Down),
+            (Direction::Up, KeyCode::Up),
+            (Direction::Right, KeyCode::Right),
+        ])
+    }
+}
+
+#[derive(Default)]
+struct RemapProgress {
+    action: usize,
+    // The last key turned down for being a hotkey, shown until the next press.
+    rejected: Option<KeyCode>,
+}
+
+#[derive(Component)]
+struct RemapText;
+
+#[derive(SystemLabel, Clone, Hash, Debug, Eq, PartialEq)]
+pub enum SnakeMovement {
+    Input,
+    Movement,
+    Eating,
+    Growth,
+}
+
+fn movement_step(
+    clock: Res<GameClock>,
+    state: Res<State<GameState>>,
+    max_catchup: Res<MaxCatchupSteps>,
+    mut movement: ResMut<MovementTimer>,
+) -> ShouldRun {
+    // Only collect elapsed time on the first check of a frame; the timer is frozen while paused.
+    if !movement.looping && *state.current() == GameState::Playing {
+        movement.timer.tick(clock.delta);
+        movement.pending_steps += movement.timer.times_finished();
+        // Time beyond the cap is dropped rather than carried over to later frames.
+        movement.pending_steps = movement.pending_steps.min(**max_catchup);
+    }
+
+    if movement.pending_steps > 0 {
+        movement.pending_steps -= 1;
+        movement.looping = true;
+        ShouldRun::YesAndCheckAgain
+    } else {
+        movement.looping = false;
+        ShouldRun::No
+    }
+}
+
+// Sleeps off the rest of each frame. Movement and animations run on GameClock, so a lower
+// frame rate doesn't slow the game down, as long as a frame spans no more than MaxCatchupSteps
+// steps.
+fn frame_limiter(max_fps: Res<MaxFps>, mut last_frame: Local<Option<Instant>>) {
+    let fps = match max_fps.0 {
+        Some(fps) => fps,
+        None => return,
+    };
+    let frame = Duration::from_secs_f32(1.0 / fps);
+    if let Some(elapsed) = last_frame.map(|last| last.elapsed()) {
+        if elapsed < frame {
+            std::thread::sleep(frame - elapsed);
+        }
+    }
+    *last_frame = Some(Instant::now());
+}
+
+fn game_clock(
+    time: Res<Time>,
+    speed: Res<GameSpeed>,
+    state: Res<State<GameState>>,
+    mut clock: ResMut<GameClock>,
+) {
+    clock.delta = if *state.current() == GameState::Playing {
+        speed.scale(time.delta())
+    } else {
+        Duration::ZERO
+    };
+}
+
+fn set_window_icon(windows: NonSend<WinitWindows>, config: Res<WindowConfig>) {
+    let path = match &config.icon_path {
+        Some(path) => path,
+        None => return,
+    };
+    let window = match windows.get_window(WindowId::primary()) {
+        Some(window) => window,
+        None => return,
+    };
+
+    let image = match image::open(path) {
+        Ok(image) => image.into_rgba8(),
+        Err(err) => {
+            warn!("Could not load window icon {}: {}", path, err);
+            return;
+        }
+    };
+    let (width, height) = image.dimensions();
+    match Icon::from_rgba(image.into_raw(), width, height) {
+        Ok(icon) => window.set_window_icon(Some(icon)),
+        Err(err) => warn!("Invalid window icon {}: {}", path, err),
+    }
+}
+
+fn setup_camera(mut commands: Commands) {
+    commands
+        .spawn_bundle(OrthographicCameraBundle::new_2d())
+        .insert(MainCamera);
+    commands.spawn_bundle(UiCameraBundle::default());
+}
+
+fn hud_text(font: &UiFont) -> TextBundle {
+    TextBundle {
+        style: Style {
+            margin: Rect {
+                right: Val::Px(24.0),
+                ..default()
+            },
+            ..default()
+        },
+        text: Text::with_section(
+            "",
+            TextStyle {
+                font: font.0.clone(),
+                font_size: HUD_FONT_SIZE,
+                color: HUD_COLOR,
+            },
+            default(),
+        ),
+        ..default()
+    }
+}
+
+fn setup_hud(mut commands: Commands, font: Res<UiFont>) {
+    commands
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    top: Val::Px(8.0),
+                    left: Val::Px(12.0),
+                    ..default()
+                },
+                align_items: AlignItems::FlexStart,
+                ..default()
+            },
+            color: UiColor(Color::NONE),
+            ..default()
+        })
+        .insert(HudRoot)
+        .with_children(|parent| {
+            parent.spawn_bundle(hud_text(&font)).insert(ScoreText {
+                pulse: Timer::from_seconds(SCORE_PULSE_SECS, false),
+                flash: SCORE_PULSE_COLOR,
+            });
+            parent.spawn_bundle(hud_text(&font)).insert(TeleportText);
+            parent.spawn_bundle(hud_text(&font)).insert(SpeedrunText);
+            parent.spawn_bundle(hud_text(&font)).insert(PaceText);
+            parent.spawn_bundle(hud_text(&font)).insert(ResumeText);
+            parent.spawn_bundle(hud_text(&font)).insert(FreezeText);
+        });
+}
+
+fn hud_layout(
+    keyboard_input: Res<Input<KeyCode>>,
+    mut layout: ResMut<HudLayout>,
+    mut roots: Query<(&mut Style, &Children), With<HudRoot>>,
+    mut visibilities: Query<&mut Visibility, Without<HudRoot>>,
+) {
+    if keyboard_input.just_pressed(HUD_LAYOUT_KEY) {
+        *layout = layout.next();
+    }
+    if !layout.is_changed() {
+        return;
+    }
+    for (mut style, children) in roots.iter_mut() {
+        // UI space is y-up, so a top-down stack is a reversed column.
+        (style.display, style.flex_direction) = match *layout {
+            HudLayout::Stacked => (Display::Flex, FlexDirection::ColumnReverse),
+            HudLayout::Compact => (Display::Flex, FlexDirection::Row),
+            HudLayout::Hidden => (Display::None, FlexDirection::Row),
+        };
+        // Visibility doesn't carry over to children, so hide the texts themselves.
+        for child in children.iter() {
+            if let Ok(mut visibility) = visibilities.get_mut(*child) {
+                visibility.is_visible = *layout != HudLayout::Hidden;
+            }
+        }
+    }
+}
+
+fn setup_grid_labels(mut commands: Commands, font: Res<UiFont>, arena: Res<ArenaSize>) {
+    spawn_grid_labels(&mut commands, &font, &arena, false);
+}
+
+fn spawn_grid_labels(commands: &mut Commands, font: &UiFont, arena: &ArenaSize, visible: bool) {
+    // Column indices along the bottom edge and row indices along the left edge.
+    let columns = (0..arena.width as i32).map(|x| (Position { x, y: 0 }, x));
+    let rows = (1..arena.height as i32).map(|y| (Position { x: 0, y }, y));
+    for (position, index) in columns.chain(rows) {
+        commands
+            .spawn_bundle(Text2dBundle {
+                text: Text::with_section(
+                    index.to_string(),
+                    TextStyle {
+                        font: font.0.clone(),
+                        font_size: GRID_LABEL_FONT_SIZE,
+                        color: GRID_LABEL_COLOR,
+                    },
+                    TextAlignment {
+                        vertical: VerticalAlign::Center,
+                        horizontal: HorizontalAlign::Center,
+                    },
+                ),
+                visibility: Visibility {
+                    is_visible: visible,
+                },
+                ..default()
+            })
+            .insert(GridLabel)
+            .insert(position)
+            .insert(ZLayer::Grid);
+    }
+}
+
+fn setup_projection(mut commands: Commands) {
+    for tick in 0..PROJECTION_TICKS {
+        commands
+            .spawn_bundle(SpriteBundle {
+                sprite: Sprite {
+                    color: PROJECTION_COLOR,
+                    ..default()
+                },
+                visibility: Visibility { is_visible: false },
+                ..default()
+            })
+            .insert(ProjectionGhost(tick))
+            .insert(Position { x: 0, y: 0 })
+            .insert(ZLayer::Grid)
+            .insert(Size::square(0.5));
+    }
+}
+
+fn projection_ghost(
+    keyboard_input: Res<Input<KeyCode>>,
+    state: Res<State<GameState>>,
+    arena: Res<ArenaSize>,
+    mut show: ResMut<ShowProjection>,
+    heads: Query<(&SnakeHead, &Position), Without<ProjectionGhost>>,
+    inputs: Query<&InputBuffer>,
+    mut ghosts: Query<(&ProjectionGhost, &mut Position, &mut Visibility)>,
+) {
+    if keyboard_input.just_pressed(PROJECTION_KEY) && *state.current() == GameState::Playing {
+        show.0 = !show.0;
+    }
+    let head = heads.iter().next().filter(|_| show.0);
+    let (mut direction, mut position) = match head {
+        Some((head, position)) => (head.direction, *position),
+        None => {
+            for (_, _, mut visibility) in ghosts.iter_mut() {
+                visibility.is_visible = false;
+            }
+            return;
+        }
+    };
+
+    // Follow the path snake_movement would take, one buffered turn per tick.
+    let mut queued = inputs
+        .iter()
+        .next()
+        .map(|input_buffer| input_buffer.inputs.clone())
+        .unwrap_or_default();
+    let mut path = Vec::with_capacity(PROJECTION_TICKS);
+    for _ in 0..PROJECTION_TICKS {
+        while let Some(input) = queued.pop_front() {
+            if input.opposite() != direction && input != direction {
+                direction = input;
+                break;
+            }
+        }
+        position = position.step(direction);
+        if !position.in_arena(&arena) {
+            break;
+        }
+        path.push(position);
+    }
+
+    for (ghost, mut ghost_position, mut visibility) in ghosts.iter_mut() {
+        match path.get(ghost.0) {
+            Some(projected) => {
+                *ghost_position = *projected;
+                visibility.is_visible = true;
+            }
+            None => visibility.is_visible = false,
+        }
+    }
+}
+
+fn setup_spawn_markers(mut commands: Commands, debug: Res<DebugMode>) {
+    if !debug.0 {
+        return;
+    }
+    for index in 0..SPAWN_MARKER_COUNT {
+        commands
+            .spawn_bundle(SpriteBundle {
+                visibility: Visibility { is_visible: false },
+                ..default()
+            })
+            .insert(SpawnMarker(index))
+            .insert(Position { x: 0, y: 0 })
+            .insert(ZLayer::Grid)
+            .insert(Size::square(0.3));
+    }
+}
+
+fn spawn_markers(
+    keyboard_input: Res<Input<KeyCode>>,
+    spawns: Res<RecentFoodSpawns>,
+    mut markers: Query<(&SpawnMarker, &mut Position, &mut Sprite, &mut Visibility)>,
+) {
+    let held = keyboard_input.pressed(SPAWN_MARKER_KEY);
+    for (marker, mut position, mut sprite, mut visibility) in markers.iter_mut() {
+        match spawns.get(marker.0).filter(|_| held) {
+            Some(spawn) => {
+                *position = *spawn;
+                // Older spawns fade out.
+                let mut color = SPAWN_MARKER_COLOR;
+                color.set_a((marker.0 + 1) as f32 / spawns.len() as f32);
+                sprite.color = color;
+                visibility.is_visible = true;
+            }
+            None => visibility.is_visible = false,
+        }
+    }
+}
+
+fn toggle_grid_labels(
+    keyboard_input: Res<Input<KeyCode>>,
+    mut labels: Query<&mut Visibility, With<GridLabel>>,
+) {
+    if keyboard_input.just_pressed(GRID_LABEL_KEY) {
+        for mut visibility in labels.iter_mut() {
+            visibility.is_visible = !visibility.is_visible;
+        }
+    }
+}
+
+fn speedrun_timer(
+    time: Res<Time>,
+    mut growth_reader: EventReader<GrowthEvent>,
+    mut speedrun: ResMut<Speedrun>,
+    mut texts: Query<&mut Text, With<SpeedrunText>>,
+) {
+    let target = match speedrun.target {
+        Some(target) => target,
+        None => return,
+    };
+
+    // The clock starts with the first food and only runs while playing.
+    let eaten = growth_reader.iter().count() as u32;
+    if !speedrun.running && !speedrun.finished && eaten > 0 {
+        speedrun.running = true;
+    }
+    if speedrun.running {
+        speedrun.points += eaten * FOOD_SCORE;
+        speedrun.elapsed += time.delta_seconds();
+        if speedrun.points >= target {
+            speedrun.running = false;
+            speedrun.finished = true;
+            info!("Reached {} points in {:.2}s", target, speedrun.elapsed);
+        }
+    }
+
+    for mut text in texts.iter_mut() {
+        text.sections[0].value = if speedrun.finished {
+            format!("{} points: {:.2}s", target, speedrun.elapsed)
+        } else {
+            format!("{:.2}s", speedrun.elapsed)
+        };
+    }
+}
+
+fn movement_freeze(
+    time: Res<Time>,
+    speed: Res<GameSpeed>,
+    mut freeze: ResMut<MovementFreeze>,
+    mut game_over_reader: EventReader<GameOverEvent>,
+    mut texts: Query<&mut Text, With<FreezeText>>,
+) {
+    if game_over_reader.iter().next().is_some() {
+        freeze.0 = None;
+    }
+    if let Some(timer) = freeze.0.as_mut() {
+        if timer.tick(speed.scale(time.delta())).finished() {
+            freeze.0 = None;
+        }
+    }
+    let label = match &freeze.0 {
+        Some(timer) => format!(
+            "Frozen: {:.1}s",
+            timer.duration().as_secs_f32() - timer.elapsed_secs()
+        ),
+        None => String::new(),
+    };
+    for mut text in texts.iter_mut() {
+        if text.sections[0].value != label {
+            text.sections[0].value = label.clone();
+        }
+    }
+}
+
+fn score_text(
+    score: Res<Score>,
+    clock: Res<GameClock>,
+    reduced_motion: Res<ReducedMotion>,
+    mut tick_reader: EventReader<TickEvent>,
+    mut query: Query<(&mut Text, &mut ScoreText)>,
+) {
+    let stepped = tick_reader.iter().last().map(|TickEvent(tick)| *tick);
+    for (mut text, mut score_text) in query.iter_mut() {
+        if score.is_changed() {
+            text.sections[0].value = format!("Score: {}", **score);
+        }
+        if let Some(tick) = stepped.filter(|_| !reduced_motion.0) {
+            score_text.pulse.reset();
+            // Every few steps the flash is brighter, like a metronome's downbeat.
+            score_text.flash = if tick % SCORE_BEAT_TICKS == 0 {
+                SCORE_BEAT_COLOR
+            } else {
+                SCORE_PULSE_COLOR
+            };
+        }
+        // Flashes on each step and fades back to the normal HUD color before the next one.
+        score_text.pulse.tick(clock.delta);
+        let fade = score_text.pulse.percent();
+        text.sections[0].style.color = Vec4::from(score_text.flash)
+            .lerp(Vec4::from(HUD_COLOR), fade)
+            .into();
+    }
+}
+
+fn resume_text(pending: Res<PendingSession>, mut query: Query<&mut Text, With<ResumeText>>) {
+    if pending.is_changed() {
+        for mut text in query.iter_mut() {
+            text.sections[0].value = match pending.0 {
+                Some(_) => "F10: resume last session".to_string(),
+                None => String::new(),
+            };
+        }
+    }
+}
+
+fn teleport_text(charges: Res<TeleportCharges>, mut query: Query<&mut Text, With<TeleportText>>) {
+    if charges.is_changed() {
+        for mut text in query.iter_mut() {
+            text.sections[0].value = format!("Teleports: {}", **charges);
+        }
+    }
+}
+
+fn setup_circle_texture(mut commands: Commands, mut images: ResMut<Assets<Image>>) {
+    let radius = CIRCLE_TEXTURE_SIZE as f32 / 2.0;
+    let mut data = Vec::with_capacity((CIRCLE_TEXTURE_SIZE * CIRCLE_TEXTURE_SIZE * 4) as usize);
+    for y in 0..CIRCLE_TEXTURE_SIZE {
+        for x in 0..CIRCLE_TEXTURE_SIZE {
+            let dx = x as f32 + 0.5 - radius;
+            let dy = y as f32 + 0.5 - radius;
+            // One pixel wide falloff at the rim so scaled-up circles stay smooth.
+            let alpha = (radius - (dx * dx + dy * dy).sqrt()).clamp(0.0, 1.0);
+            data.extend_from_slice(&[255, 255, 255, (alpha * 255.0) as u8]);
+        }
+    }
+
+    let image = Image::new(
+        Extent3d {
+            width: CIRCLE_TEXTURE_SIZE,
+            height: CIRCLE_TEXTURE_SIZE,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        data,
+        TextureFormat::Rgba8UnormSrgb,
+    );
+    commands.insert_resource(CircleTexture(images.add(image)));
+}
+
+fn load_font(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.insert_resource(UiFont(asset_server.load(FONT_PATH)));
+}
+
+fn snake_sprite(color: Color, style: &SpriteStyle, circle: &CircleTexture) -> SpriteBundle {
+    match style {
+        SpriteStyle::Square => SpriteBundle {
+            sprite: Sprite { color, ..default() },
+            ..default()
+        },
+        SpriteStyle::Circle => SpriteBundle {
+            sprite: Sprite {
+                color,
+                // Keep the unit size of the default square so size_scaling works unchanged.
+                custom_size: Some(Vec2::ONE),
+                ..default()
+            },
+            texture: circle.0.clone(),
+            ..default()
+        },
+    }
+}
+
+fn load_scenario(
+    file: Res<ScenarioFile>,
+    arena: Res<ArenaSize>,
+    border: Res<BorderWall>,
+    mut scenario: ResMut<Scenario>,
+) {
+    let path = match &file.0 {
+        Some(path) => path,
+        None => return,
+    };
+    let result = std::fs::read_to_string(path)
+        .map_err(|err| err.to_string())
+        .and_then(|json| serde_json::from_str::<GameSnapshot>(&json).map_err(|err| err.to_string()))
+        .and_then(|snapshot| {
+            snapshot
+                .validate_scenario(&arena, &border)
+                .map(|()| snapshot)
+        });
+    match result {
+        Ok(snapshot) => scenario.0 = Some(snapshot),
+        Err(err) => warn!("Ignoring scenario {}: {}", path, err),
+    }
+}
+
+fn spawn_snake(
+    commands: &mut Commands,
+    cells: &[Position],
+    direction: Direction,
+    look: &SnakeLook,
+) -> Vec<Entity> {
+    let body_end = if look.two_headed.0 {
+        cells.len().saturating_sub(1).max(1)
+    } else {
+        cells.len()
+    };
+    let mut segments = vec![spawn_head(commands, cells[0], direction, look)];
+    for pos in &cells[1..body_end] {
+        segments.push(spawn_segment(commands, *pos, look));
+    }
+    if body_end < cells.len() {
+        let back = back_direction(cells).unwrap_or_else(|| direction.opposite());
+        segments.push(spawn_head(commands, cells[body_end], back, look));
+    }
+    segments
+}
+
+// Which way the last cell faces, away from the first cell before it that isn't stacked on it.
+fn back_direction(cells: &[Position]) -> Option<Direction> {
+    let (back, rest) = cells.split_last()?;
+    let neck = rest.iter().rev().find(|pos| *pos != back)?;
+    neck.direction_to(*back)
+}
+
+// Everything setup_snake_game needs to lay out a fresh round.
+#[derive(SystemParam)]
+struct RoundSetup<'w, 's> {
+    food_count: Res<'w, FoodCount>,
+    food_timeout: Res<'w, FoodTimeout>,
+    scenario: Res<'w, Scenario>,
+    border: Res<'w, BorderWall>,
+    look: SnakeLook<'w, 's>,
+}
+
+fn setup_snake_game(
+    mut commands: Commands,
+    mut food_writer: EventWriter<FoodEvent>,
+    mut segments: ResMut<SnakeSegments>,
+    setup: RoundSetup,
+) {
+    let RoundSetup {
+        food_count,
+        food_timeout,
+        scenario,
+        border,
+        look,
+    } = setup;
+    let placed = match &scenario.0 {
+        Some(snapshot) => {
+            *segments = SnakeSegments(spawn_snake(
+                &mut commands,
+                &snapshot.segments,
+                snapshot.direction,
+                &look,
+            ));
+            for food in &snapshot.food {
+                spawn_saved_food(&mut commands, food, &food_timeout);
+            }
+            for pos in &snapshot.walls {
+                spawn_wall(&mut commands, *pos);
+            }
+            snapshot.food.len()
+        }
+        None => {
+            *segments = SnakeSegments(spawn_snake(
+                &mut commands,
+                &border.start(),
+                Direction::Up,
+                &look,
+            ));
+            0
+        }
+    };
+    // Food a scenario leaves out is placed as usual.
+    for _ in placed..**food_count {
+        food_writer.send(FoodEvent);
+    }
+}
+
+fn spawn_wall(commands: &mut Commands, position: Position) -> Entity {
+    commands
+        .spawn_bundle(SpriteBundle::default())
+        .insert(Wall)
+        .insert(position)
+        .insert(ZLayer::Wall)
+        .insert(Size::square(1.0))
+        .id()
+}
+
+// Swaps the walls placed during a round for the ones a snapshot recorded.
+fn restore_walls(
+    commands: &mut Commands,
+    walls: impl Iterator<Item = Entity>,
+    snapshot: &GameSnapshot,
+) {
+    for ent in walls {
+        commands.entity(ent).despawn_recursive();
+    }
+    for pos in &snapshot.walls {
+        spawn_wall(commands, *pos);
+    }
+}
+
+fn clamp_border_wall(mut border: ResMut<BorderWall>) {
+    if border.thickness > MAX_BORDER_THICKNESS {
+        warn!(
+            "A {}-tile border leaves no room to play; using {}",
+            border.thickness, MAX_BORDER_THICKNESS
+        );
+        border.thickness = MAX_BORDER_THICKNESS;
+    }
+}
+
+fn setup_border_wall(mut commands: Commands, border: Res<BorderWall>, arena: Res<ArenaSize>) {
+    spawn_border(&mut commands, &border, &arena);
+}
+
+fn spawn_border(commands: &mut Commands, border: &BorderWall, arena: &ArenaSize) {
+    for position in arena.cells() {
+        if border.covers(arena, &position) {
+            let wall = spawn_wall(commands, position);
+            commands.entity(wall).insert(BorderTile);
+        }
+    }
+}
+
+fn setup_ice(
+    mut commands: Commands,
+    coverage: Res<IceCoverage>,
+    border: Res<BorderWall>,
+    arena: Res<ArenaSize>,
+) {
+    let start = border.start();
+    for x in 0..arena.width as i32 {
+        for y in 0..arena.height as i32 {
+            let position = Position { x, y };
+            if start.contains(&position) || random::<f32>() >= coverage.0 {
+                continue;
+            }
+            commands
+                .spawn_bundle(SpriteBundle {
+                    sprite: Sprite {
+                        color: ICE_COLOR,
+                        ..default()
+                    },
+                    ..default()
+                })
+                .insert(IceTile)
+                .insert(position)
+                .insert(ZLayer::Background)
+                .insert(Size::square(1.0));
+        }
+    }
+}
+
+// Everything spawn_head and spawn_segment need to draw the snake.
+#[derive(SystemParam)]
+struct SnakeLook<'w, 's> {
+    style: Res<'w, SpriteStyle>,
+    circle: Res<'w, CircleTexture>,
+    show_tongue: Res<'w, ShowTongue>,
+    outline: Res<'w, Outline>,
+    two_headed: Res<'w, TwoHeaded>,
+    #[system_param(ignore)]
+    marker: PhantomData<&'s ()>,
+}
+
+fn spawn_outline(parent: &mut ChildBuilder, look: &SnakeLook) {
+    if look.outline.thickness <= 0.0 {
+        return;
+    }
+    let mut outline = snake_sprite(look.outline.color, &look.style, &look.circle);
+    // The transform is local to the segment, so the outline follows its position and
+    // size_scaling without a Position of its own.
+    outline.transform = Transform {
+        translation: Vec3::new(0.0, 0.0, -0.5),
+        scale: Vec3::splat(1.0 + look.outline.thickness),
+        ..default()
+    };
+    parent.spawn_bundle(outline);
+}
+
+fn spawn_head(
+    commands: &mut Commands,
+    position: Position,
+    direction: Direction,
+    look: &SnakeLook,
+) -> Entity {
+    commands
+        .spawn_bundle(snake_sprite(SNAKE_HEAD_COLOR, &look.style, &look.circle))
+        .insert(SnakeHead { direction })
+        .insert(SnakeSegment)
+        .insert(position)
+        .insert(ZLayer::Head)
+        .insert(Size::square(0.8))
+        .with_children(|parent| {
+            spawn_outline(parent, look);
+            if look.show_tongue.0 {
+                parent
+                    .spawn_bundle(SpriteBundle {
+                        sprite: Sprite {
+                            color: TONGUE_COLOR,
+                            ..default()
+                        },
+                        ..default()
+                    })
+                    .insert(Tongue {
+                        timer: Timer::from_seconds(TONGUE_INTERVAL_SECS, true),
+                    });
+            }
+        })
+        .id()
+}
+
+fn spawn_segment(commands: &mut Commands, position: Position, look: &SnakeLook) -> Entity {
+    commands
+        .spawn_bundle(snake_sprite(SNAKE_SEGMENT_COLOR, &look.style, &look.circle))
+        .insert(SnakeSegment)
+        .insert(position)
+        .insert(ZLayer::Body)
+        .insert(Size::square(0.65))
+        .with_children(|parent| spawn_outline(parent, look))
+        .id()
+}
+
+fn spawn_food(commands: &mut Commands, position: Position, timeout: &FoodTimeout) -> Entity {
+    let mut food = commands.spawn_bundle(SpriteBundle {
+        sprite: Sprite {
+            color: FOOD_COLOR,
+            ..default()
+        },
+        ..default()
+    });
+    food.insert(Food)
+        .insert(position)
+        .insert(ZLayer::Food)
+        .insert(Size::square(0.8))
+        .insert(SpawnAnim {
+            timer: Timer::from_seconds(FOOD_SPAWN_ANIM_SECS, false),
+        });
+    if let Some(secs) = timeout.0 {
+        food.insert(FoodLifetime(Timer::from_seconds(secs, false)));
+    }
+    food.id()
+}
+
+fn spawn_saved_food(commands: &mut Commands, saved: &SavedFood, timeout: &FoodTimeout) -> Entity {
+    let food = spawn_food(commands, saved.position, timeout);
+    match saved.kind {
+        FoodKind::Plain => {}
+        FoodKind::Freeze => {
+            commands.entity(food).insert(FreezeFood);
+        }
+        FoodKind::Smart { moves_left } => {
+            commands.entity(food).insert(SmartFood { moves_left });
+        }
+    }
+    food
+}
+
+fn init_inputs(mut commands: Commands) {
+    commands.spawn().insert(InputBuffer {
+        inputs: VecDeque::with_capacity(10),
+    });
+}
+
+// The events snake_movement sends, grouped for the same reason as MovementRules.
+#[derive(SystemParam)]
+struct MovementEvents<'w, 's> {
+    game_over: EventWriter<'w, 's, GameOverEvent>,
+    food: EventWriter<'w, 's, FoodEvent>,
+    tick: EventWriter<'w, 's, TickEvent>,
+}
+
+// The settings snake_movement runs by, grouped to keep its parameter list in check.
+#[derive(SystemParam)]
+struct MovementRules<'w, 's> {
+    buffer_mode: Res<'w, BufferMode>,
+    turn_smoothing: Res<'w, TurnSmoothing>,
+    coalesce_inputs: Res<'w, CoalesceInputs>,
+    exempt_neck: Res<'w, ExemptNeck>,
+    lone_head_reverse: Res<'w, LoneHeadReverse>,
+    shed_on_wall: Res<'w, ShedOnWall>,
+    survival_score: Res<'w, SurvivalScorePerTick>,
+    two_headed: Res<'w, TwoHeaded>,
+    arena: Res<'w, ArenaSize>,
+    #[system_param(ignore)]
+    marker: PhantomData<&'s ()>,
+}
+
+fn snake_movement(
+    mut commands: Commands,
+    mut last_tail_position: ResMut<LastTailPosition>,
+    mut events: MovementEvents,
+    mut history: ResMut<RewindHistory>,
+    mut tick: ResMut<TickCount>,
+    mut grace: ResMut<StartGrace>,
+    freeze: Res<MovementFreeze>,
+    mut score: ResMut<Score>,
+    rules: MovementRules,
+    mut segments: ResMut<SnakeSegments>,
+    mut heads: Query<(Entity, &mut SnakeHead)>,
+    mut position: Query<&mut Position>,
+    mut inputs: Query<&mut InputBuffer>,
+    food: Query<(Entity, Option<&FreezeFood>, Option<&SmartFood>), With<Food>>,
+    ice: Query<Entity, With<IceTile>>,
+    walls: Query<(Entity, Option<&BorderTile>), With<Wall>>,
+) {
+    // Inputs are still buffered during the grace period; only the movement waits.
+    if **grace > 0 {
+        **grace -= 1;
+        return;
+    }
+    // Same while frozen, so a turn queued meanwhile applies on the first step after.
+    if freeze.0.is_some() {
+        return;
+    }
+    // The head that trailed on the last tick leads on this one.
+    if rules.two_headed.0 && segments.len() > 1 {
+        segments.reverse();
+    }
+
+    let front = segments
+        .first()
+        .and_then(|front| heads.get_mut(*front).ok());
+    if let Some((head_entity, mut head)) = front {
+        **tick += 1;
+
+        let segment_positions = segments
+            .iter()
+            .map(|e| *position.get_mut(*e).unwrap())
+            .collect::<Vec<Position>>();
+
+        let wall_cells = walls
+            .iter()
+            .map(|(e, _)| *position.get(e).unwrap())
+            .collect::<Vec<Position>>();
+
+        if history.len() == REWIND_HISTORY_LEN {
+            history.pop_front();
+        }
+        history.push_back(GameSnapshot {
+            segments: segment_positions.clone(),
+            direction: head.direction,
+            food: food
+                .iter()
+                .map(|(e, freeze, smart)| SavedFood::new(*position.get(e).unwrap(), freeze, smart))
+                .collect(),
+            walls: walls
+                .iter()
+                .filter(|(_, border)| border.is_none())
+                .map(|(e, _)| *position.get(e).unwrap())
+                .collect(),
+        });
+
+        let on_ice = ice
+            .iter()
+            .any(|e| *position.get(e).unwrap() == segment_positions[0]);
+        let mut head_pos = position.get_mut(head_entity).unwrap();
+
+        let may_reverse = rules.lone_head_reverse.0 && segments.len() == 1;
+        let legal = |input: Direction, current: Direction| {
+            input != current && (may_reverse || input.opposite() != current)
+        };
+        if *rules.buffer_mode == BufferMode::Buffered {
+            if let Some(mut input_buffer) = inputs.iter_mut().next() {
+                // The head slides straight across ice, dropping any turns queued meanwhile.
+                if on_ice {
+                    input_buffer.inputs.clear();
+                }
+                if rules.turn_smoothing.0 {
+                    // A run like Right, Up, Right only whips the head back and forth, so only
+                    // the last turn of it is kept.
+                    let run = std::iter::once(head.direction)
+                        .chain(input_buffer.inputs.iter().copied())
+                        .collect::<Vec<Direction>>()
+                        .windows(3)
+                        .take_while(|turns| turns[0] == turns[2])
+                        .count();
+                    input_buffer.inputs.drain(..run);
+                }
+                if rules.coalesce_inputs.0 {
+                    // Only the last legal turn pressed since the previous tick counts; ending
+                    // on the current heading cancels the turns before it.
+                    let last_legal =
+                        input_buffer.inputs.drain(..).rev().find(|input| {
+                            *input == head.direction || legal(*input, head.direction)
+                        });
+                    if let Some(input) = last_legal {
+                        head.direction = input;
+                    }
+                }
+                while let Some(input) = input_buffer.inputs.pop_front() {
+                    if legal(input, head.direction) {
+                        head.direction = input;
+                        break;
+                    }
+                }
+            }
+        }
+
+        match &head.direction {
+            Direction::Left => {
+                head_pos.x -= 1;
+            }
+            Direction::Right => {
+                head_pos.x += 1;
+            }
+            Direction::Up => {
+                head_pos.y += 1;
+            }
+            Direction::Down => {
+                head_pos.y -= 1;
+            }
+        }
+
+        let hits_wall = !head_pos.in_arena(&rules.arena) || wall_cells.contains(&head_pos);
+        if hits_wall && rules.shed_on_wall.0 && segments.len() > 1 {
+            *head_pos = segment_positions[0];
+            let keep = segments.len().div_ceil(2);
+            // A back head is kept too, pulled in right behind the front half.
+            let back = rules.two_headed.0.then(|| segments.pop()).flatten();
+            for ent in segments.drain(keep..) {
+                commands.entity(ent).despawn_recursive();
+            }
+            // Turn off the wall where there's room, or the next tick would shed again.
+            let turns = match head.direction {
+                Direction::Up | Direction::Down => [Direction::Left, Direction::Right],
+                Direction::Left | Direction::Right => [Direction::Up, Direction::Down],
+            };
+            let kept = &segment_positions[..keep];
+            if let Some(turn) = turns.into_iter().find(|turn| {
+                let cell = head_pos.step(*turn);
+                cell.in_arena(&rules.arena) && !wall_cells.contains(&cell) && !kept.contains(&cell)
+            }) {
+                head.direction = turn;
+            }
+            let mut tail = segment_positions[keep - 1];
+            if let Some(back) = back {
+                tail = segment_positions[keep];
+                *position.get_mut(back).unwrap() = tail;
+                segments.push(back);
+                if let (Ok((_, mut back_head)), Some(direction)) = (
+                    heads.get_mut(back),
+                    back_direction(&segment_positions[..=keep]),
+                ) {
+                    back_head.direction = direction;
+                }
+            }
+            *last_tail_position = LastTailPosition(Some(tail));
+            **score += rules.survival_score.0;
+            events.tick.send(TickEvent(**tick));
+            return;
+        }
+
+        let mut died = false;
+        if !head_pos.in_arena(&rules.arena) {
+            events.game_over.send(GameOverEvent);
+            events.food.send(FoodEvent);
+            died = true;
+        }
+
+        let hits_body = segment_positions
+            .iter()
+            .enumerate()
+            .any(|(i, pos)| *pos == *head_pos && !(rules.exempt_neck.0 && i == 1));
+        if hits_body || wall_cells.contains(&head_pos) {
+            events.game_over.send(GameOverEvent);
+            events.food.send(FoodEvent);
+            died = true;
+        }
+
+        segment_positions
+            .iter()
+            .zip(segments.iter().skip(1))
+            .for_each(|(pos, segment)| {
+                *position.get_mut(*segment).unwrap() = *pos;
+            });
+        *last_tail_position = LastTailPosition(Some(*segment_positions.last().unwrap()));
+        if rules.two_headed.0 {
+            // The back head has followed the body, so it faces away from its new neck.
+            let cells = segments
+                .iter()
+                .map(|e| *position.get(*e).unwrap())
+                .collect::<Vec<Position>>();
+            if let (Some(back), Some(direction)) = (segments.last(), back_direction(&cells)) {
+                if let Ok((_, mut back_head)) = heads.get_mut(*back) {
+                    back_head.direction = direction;
+                }
+            }
+        }
+        if !died {
+            **score += rules.survival_score.0;
+            events.tick.send(TickEvent(**tick));
+        }
+    }
+}
+
+fn snake_movement_input(
+    keyboard_input: Res<Input<KeyCode>>,
+    bindings: Res<KeyBindings>,
+    buffer_mode: Res<BufferMode>,
+    segments: Res<SnakeSegments>,
+    mut heads: Query<&mut SnakeHead>,
+    positions: Query<&Position>,
+    ice: Query<&Position, With<IceTile>>,
+    turbo_tap: Res<TurboTap>,
+    lone_head_reverse: Res<LoneHeadReverse>,
+    two_headed: Res<TwoHeaded>,
+    mut movement: ResMut<MovementTimer>,
+    mut inputs: Query<&mut InputBuffer>,
+) {
+    let direction = match bindings
+        .iter()
+        .find(|(_, key)| keyboard_input.just_pressed(*key))
+    {
+        Some((direction, _)) => *direction,
+        None => return,
+    };
+
+    // With turns still queued the heading is about to change, so only an idle press counts.
+    let idle = inputs
+        .iter()
+        .next()
+        .is_none_or(|input_buffer| input_buffer.inputs.is_empty());
+    // With two heads, turns go to the one that moves on the next tick.
+    let order = if two_headed.0 {
+        segments.iter().rev().copied().collect::<Vec<Entity>>()
+    } else {
+        segments.to_vec()
+    };
+    let heading = order
+        .first()
+        .and_then(|e| heads.get(*e).ok())
+        .map(|head| head.direction);
+    if turbo_tap.0 && idle && heading == Some(direction) {
+        // The extra step runs through snake_movement like any other, collisions included.
+        movement.pending_steps += 1;
+        return;
+    }
+
+    let may_reverse = lone_head_reverse.0 && segments.len() == 1;
+    match *buffer_mode {
+        BufferMode::Buffered => {
+            if let Some(mut input_buffer) = inputs.iter_mut().next() {
+                // Validate against the last queued turn rather than the current direction, so
+                // every queued turn is legal relative to the one committed right before it.
+                let previous = input_buffer.inputs.back().copied().or(heading);
+                let legal = previous.is_none_or(|previous| {
+                    direction != previous && (may_reverse || direction != previous.opposite())
+                });
+                if input_buffer.inputs.len() < 3 && legal {
+                    input_buffer.inputs.push_back(direction);
+                }
+            }
+        }
+        BufferMode::Immediate => {
+            if let Some(mut head) = order.first().and_then(|e| heads.get_mut(*e).ok()) {
+                let head_pos = order.first().and_then(|e| positions.get(*e).ok());
+                if head_pos.is_some_and(|head_pos| ice.iter().any(|tile| tile == head_pos)) {
+                    return;
+                }
+                // Several turns can land between two ticks, so check against the neck rather
+                // than the current direction to rule out turning back into the body.
+                let neck_pos = order.get(1).and_then(|e| positions.get(*e).ok());
+                let reverses = match (head_pos, neck_pos) {
+                    (Some(head_pos), Some(neck_pos)) => head_pos.step(direction) == *neck_pos,
+                    _ => !may_reverse && direction == head.direction.opposite(),
+                };
+                if !reverses {
+                    head.direction = direction;
+                }
+            }
+        }
+    }
+}
+
+// Speed and pause controls for UI code, so it doesn't have to drive MovementTimer and the
+// state stack by hand.
+#[derive(SystemParam)]
+struct SnakeControls<'w, 's> {
+    movement: ResMut<'w, MovementTimer>,
+    state: ResMut<'w, State<GameState>>,
+    #[system_param(ignore)]
+    marker: PhantomData<&'s ()>,
+}
+
+impl<'w, 's> SnakeControls<'w, 's> {
+    fn step_secs(&self) -> f32 {
+        self.movement.timer.duration().as_secs_f32()
+    }
+
+    // Clamped to MIN_STEP_SECS..=MAX_STEP_SECS; returns the interval actually applied.
+    fn set_step_secs(&mut self, secs: f32) -> f32 {
+        let secs = if secs.is_finite() {
+            secs.clamp(MIN_STEP_SECS, MAX_STEP_SECS)
+        } else {
+            MOVEMENT_STEP_SECS
+        };
+        self.movement
+            .timer
+            .set_duration(Duration::from_secs_f32(secs));
+        secs
+    }
+
+    fn is_paused(&self) -> bool {
+        *self.state.current() == GameState::Paused
+    }
+
+    // Has no effect while the remap screen is open.
+    fn set_paused(&mut self, paused: bool) {
+        // A transition already queued this frame wins over this one.
+        let _ = match (self.state.current(), paused) {
+            (GameState::Playing, true) => self.state.push(GameState::Paused),
+            (GameState::Paused, false) => self.state.pop(),
+            _ => Ok(()),
+        };
+    }
+}
+
+fn pause_input(keyboard_input: Res<Input<KeyCode>>, mut controls: SnakeControls) {
+    if keyboard_input.just_pressed(PAUSE_KEY) {
+        let paused = controls.is_paused();
+        controls.set_paused(!paused);
+    }
+}
+
+fn speed_input(keyboard_input: Res<Input<KeyCode>>, mut controls: SnakeControls) {
+    let factor = if keyboard_input.just_pressed(SPEED_UP_KEY) {
+        SPEED_STEP_FACTOR
+    } else if keyboard_input.just_pressed(SLOW_DOWN_KEY) {
+        1.0 / SPEED_STEP_FACTOR
+    } else {
+        return;
+    };
+    let secs = controls.step_secs() * factor;
+    let secs = controls.set_step_secs(secs);
+    info!("Movement step set to {:.3}s", secs);
+}
+
+fn spawn_pause_overlay(
+    mut commands: Commands,
+    windows: Res<Windows>,
+    overlay: Res<PauseOverlay>,
+    cameras: Query<&Transform, With<MainCamera>>,
+) {
+    if overlay.alpha <= 0.0 {
+        return;
+    }
+    let window = windows.get_primary().unwrap();
+    // The camera may have followed the head away from the origin.
+    let center = cameras
+        .iter()
+        .next()
+        .map_or(Vec3::ZERO, |transform| transform.translation);
+    let mut color = overlay.color;
+    color.set_a(overlay.alpha);
+    commands
+        .spawn_bundle(SpriteBundle {
+            sprite: Sprite {
+                color,
+                custom_size: Some(Vec2::new(window.width(), window.height())),
+                ..default()
+            },
+            transform: Transform::from_xyz(center.x, center.y, ZLayer::Overlay.z()),
+            ..default()
+        })
+        .insert(PauseDim);
+}
+
+fn despawn_pause_overlay(mut commands: Commands, overlays: Query<Entity, With<PauseDim>>) {
+    for ent in overlays.iter() {
+        commands.entity(ent).despawn();
+    }
+}
+
+fn remap_start(
+    mut keyboard_input: ResMut<Input<KeyCode>>,
+    mut state: ResMut<State<GameState>>,
+    mut progress: ResMut<RemapProgress>,
+) {
+    if keyboard_input.just_pressed(REMAP_KEY) {
+        // Don't let the remap screen capture the key that opened it.
+        keyboard_input.clear_just_pressed(REMAP_KEY);
+        *progress = RemapProgress::default();
+        state.push(GameState::Remapping).unwrap();
+    }
+}
+
+fn spawn_remap_text(mut commands: Commands, font: Res<UiFont>) {
+    commands
+        .spawn_bundle(TextBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    top: Val::Percent(45.0),
+                    left: Val::Percent(30.0),
+                    ..default()
+                },
+                ..default()
+            },
+            text: Text::with_section(
+                "",
+                TextStyle {
+                    font: font.0.clone(),
+                    font_size: HUD_FONT_SIZE,
+                    color: HUD_COLOR,
+                },
+                default(),
+            ),
+            ..default()
+        })
+        .insert(RemapText);
+}
+
+fn despawn_remap_text(mut commands: Commands, texts: Query<Entity, With<RemapText>>) {
+    for ent in texts.iter() {
+        commands.entity(ent).despawn();
+    }
+}
+
+fn remap_input(
+    mut keyboard_input: ResMut<Input<KeyCode>>,
+    mut state: ResMut<State<GameState>>,
+    mut bindings: ResMut<KeyBindings>,
+    mut progress: ResMut<RemapProgress>,
+    mut texts: Query<&mut Text, With<RemapText>>,
+) {
+    let pressed = keyboard_input.get_just_pressed().next().copied();
+    if let Some(key) = pressed {
+        // Consume the key so it doesn't also steer the snake once we're back in Playing.
+        keyboard_input.clear_just_pressed(key);
+        if key == REMAP_CANCEL_KEY {
+            state.pop().unwrap();
+            return;
+        }
+
+        if RESERVED_KEYS.contains(&key) {
+            progress.rejected = Some(key);
+        } else {
+            // A key can only be bound once, so a conflicting action takes over the old key.
+            let index = progress.action;
+            if let Some(other) = bindings.iter().position(|(_, bound)| *bound == key) {
+                bindings[other].1 = bindings[index].1;
+            }
+            bindings[index].1 = key;
+            progress.rejected = None;
+
+            progress.action += 1;
+            if progress.action == bindings.len() {
+                state.pop().unwrap();
+                return;
+            }
+        }
+    }
+
+    for mut text in texts.iter_mut() {
+        let (direction, key) = bindings[progress.action];
+        let prompt = format!(
+            "Press a key for {:?} (currently {:?}, Esc to cancel)",
+            direction, key
+        );
+        text.sections[0].value = match progress.rejected {
+            Some(rejected) => format!("{:?} is already a hotkey. {}", rejected, prompt),
+            None => prompt,
+        };
+    }
+}
+
+// Keys rebound before a cancel stay bound, so they're saved too.
+fn save_key_bindings(bindings: Res<KeyBindings>, progress: Res<RemapProgress>) {
+    if progress.action == 0 {
+        return;
+    }
+    if let Err(err) = bindings.write(KEY_BINDINGS_PATH) {
+        warn!(
+            "Could not save key bindings to {}: {}",
+            KEY_BINDINGS_PATH, err
+        );
+    }
+}
+
+fn load_key_bindings(mut bindings: ResMut<KeyBindings>) {
+    if !std::path::Path::new(KEY_BINDINGS_PATH).exists() {
+        return;
+    }
+    match KeyBindings::read(KEY_BINDINGS_PATH) {
+        Ok(loaded) => *bindings = loaded,
+        Err(err) => warn!("Ignoring key bindings in {}: {}", KEY_BINDINGS_PATH, err),
+    }
+}
+
+fn snake_teleport(
+    keyboard_input: Res<Input<KeyCode>>,
+    state: Res<State<GameState>>,
+    mut charges: ResMut<TeleportCharges>,
+    segments: Res<SnakeSegments>,
+    arena: Res<ArenaSize>,
+    heads: Query<&SnakeHead>,
+    blockers: Query<Entity, Or<(With<Food>, With<Wall>)>>,
+    mut positions: Query<&mut Position>,
+) {
+    if !keyboard_input.just_pressed(TELEPORT_KEY)
+        || *state.current() != GameState::Playing
+        || **charges == 0
+    {
+        return;
+    }
+    let direction = match heads.iter().next() {
+        Some(head) => head.direction,
+        None => return,
+    };
+
+    let body = segments
+        .iter()
+        .map(|e| *positions.get(*e).unwrap())
+        .collect::<Vec<Position>>();
+    let blocked = blockers
+        .iter()
+        .map(|e| *positions.get(e).unwrap())
+        .collect::<Vec<Position>>();
+
+    // The whole snake is shifted so the body keeps trailing the head in the same shape.
+    let head = body[0];
+    let mut offsets = Vec::new();
+    for x in 0..arena.width as i32 {
+        for y in 0..arena.height as i32 {
+            let (dx, dy) = (x - head.x, y - head.y);
+            if dx == 0 && dy == 0 {
+                continue;
+            }
+            let shift = |pos: &Position| Position {
+                x: pos.x + dx,
+                y: pos.y + dy,
+            };
+            let body_fits = body
+                .iter()
+                .map(shift)
+                .all(|pos| pos.in_arena(&arena) && !blocked.contains(&pos));
+            // Don't land facing the edge or a wall, which would end the game on the next tick.
+            let front = shift(&head.step(direction));
+            if body_fits && front.in_arena(&arena) && !blocked.contains(&front) {
+                offsets.push((dx, dy));
+            }
+        }
+    }
+    if offsets.is_empty() {
+        return;
+    }
+
+    let (dx, dy) = offsets[(random::<f32>() * offsets.len() as f32) as usize];
+    for ent in segments.iter() {
+        let mut pos = positions.get_mut(*ent).unwrap();
+        pos.x += dx;
+        pos.y += dy;
+    }
+    **charges -= 1;
+}
+
+// Turns the snake around: the tail becomes the head and heads off away from its new neck.
+fn reverse_snake(
+    mut commands: Commands,
+    keyboard_input: Res<Input<KeyCode>>,
+    state: Res<State<GameState>>,
+    theme: Res<Theme>,
+    two_headed: Res<TwoHeaded>,
+    mut charges: ResMut<ReverseCharges>,
+    mut segments: ResMut<SnakeSegments>,
+    mut last_tail_position: ResMut<LastTailPosition>,
+    heads: Query<(&SnakeHead, Option<&Children>)>,
+    tongues: Query<(), With<Tongue>>,
+    positions: Query<&Position>,
+    mut sprites: Query<&mut Sprite, With<SnakeSegment>>,
+    mut inputs: Query<&mut InputBuffer>,
+) {
+    // With a head at each end there is nothing to turn around.
+    if !keyboard_input.just_pressed(REVERSE_KEY)
+        || *state.current() != GameState::Playing
+        || **charges == 0
+        || two_headed.0
+    {
+        return;
+    }
+    let (old_head, new_head) = match (segments.first(), segments.last()) {
+        (Some(old_head), Some(new_head)) => (*old_head, *new_head),
+        _ => return,
+    };
+    let (head, children) = match heads.get(old_head) {
+        Ok(head) => head,
+        Err(_) => return,
+    };
+
+    let old_direction = head.direction;
+    // Right after eating, the newest segments still share the tail cell, so the neck is the
+    // first segment off that cell.
+    let cells = segments
+        .iter()
+        .map(|e| *positions.get(*e).unwrap())
+        .collect::<Vec<Position>>();
+    let direction = back_direction(&cells).unwrap_or_else(|| old_direction.opposite());
+
+    if old_head != new_head {
+        commands
+            .entity(old_head)
+            .remove::<SnakeHead>()
+            .insert(ZLayer::Body)
+            .insert(Size::square(0.65));
+        commands
+            .entity(new_head)
+            .insert(ZLayer::Head)
+            .insert(Size::square(0.8));
+        // The tongue goes with the head; the outline stays on its segment.
+        if let Some(children) = children {
+            let tongue = children
+                .iter()
+                .copied()
+                .filter(|child| tongues.contains(*child))
+                .collect::<Vec<Entity>>();
+            commands.entity(old_head).remove_children(&tongue);
+            commands.entity(new_head).push_children(&tongue);
+        }
+        if let Ok(mut sprite) = sprites.get_mut(old_head) {
+            sprite.color = theme.body;
+        }
+        if let Ok(mut sprite) = sprites.get_mut(new_head) {
+            sprite.color = theme.head;
+        }
+    }
+    commands.entity(new_head).insert(SnakeHead { direction });
+
+    // The old head becomes the tail; growing now extends it where the head was heading.
+    let old_head_pos = *positions.get(old_head).unwrap();
+    *last_tail_position = LastTailPosition(Some(old_head_pos.step(old_direction)));
+    segments.reverse();
+    if let Some(mut input_buffer) = inputs.iter_mut().next() {
+        input_buffer.inputs.clear();
+    }
+    **charges -= 1;
+}
+
+fn grow_arena(
+    mut milestone_reader: EventReader<MilestoneEvent>,
+    growth: Res<ArenaGrowth>,
+    mut arena: ResMut<ArenaSize>,
+) {
+    // Grid positions stay put, so the new cells are added along the top and right edges.
+    for _ in milestone_reader.iter().filter(|_| growth.0) {
+        arena.width += 2;
+        arena.height += 2;
+    }
+}
+
+// Whatever is laid out along the arena edges follows it when it changes size.
+fn arena_relayout(
+    mut commands: Commands,
+    arena: Res<ArenaSize>,
+    mut laid_out: Local<ArenaSize>,
+    border: Res<BorderWall>,
+    font: Res<UiFont>,
+    mut heatmap: ResMut<Heatmap>,
+    border_tiles: Query<Entity, With<BorderTile>>,
+    labels: Query<(Entity, &Visibility), With<GridLabel>>,
+) {
+    if *arena == *laid_out {
+        return;
+    }
+    heatmap.resize(&laid_out, &arena);
+    for ent in border_tiles.iter() {
+        commands.entity(ent).despawn_recursive();
+    }
+    spawn_border(&mut commands, &border, &arena);
+    let visible = labels.iter().any(|(_, visibility)| visibility.is_visible);
+    for (ent, _) in labels.iter() {
+        commands.entity(ent).despawn();
+    }
+    spawn_grid_labels(&mut commands, &font, &arena, visible);
+    *laid_out = *arena;
+}
+
+fn heatmap_record(
+    mut heatmap: ResMut<Heatmap>,
+    arena: Res<ArenaSize>,
+    heads: Query<&Position, With<SnakeHead>>,
+) {
+    if let Some(pos) = heads.iter().next().filter(|pos| pos.in_arena(&arena)) {
+        heatmap[arena.index(pos)] += 1;
+    }
+}
+
+fn release_food(
+    after_grace: Res<FoodAfterGrace>,
+    grace: Res<StartGrace>,
+    food_count: Res<FoodCount>,
+    mut food_writer: EventWriter<FoodEvent>,
+) {
+    if after_grace.0 && grace.is_changed() && **grace == 0 {
+        for _ in 0..**food_count {
+            food_writer.send(FoodEvent);
+        }
+    }
+}
+
+fn heatmap_export(
+    keyboard_input: Res<Input<KeyCode>>,
+    heatmap: Res<Heatmap>,
+    arena: Res<ArenaSize>,
+) {
+    if !keyboard_input.just_pressed(HEATMAP_KEY) {
+        return;
+    }
+    // Top row first, so the file reads the same way round as the arena on screen.
+    let csv = heatmap
+        .chunks(arena.width as usize)
+        .rev()
+        .map(|row| {
+            row.iter()
+                .map(|visits| visits.to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    match std::fs::write(HEATMAP_PATH, csv + "\n") {
+        Ok(()) => info!("Exported heatmap to {}", HEATMAP_PATH),
+        Err(err) => warn!("Could not export heatmap to {}: {}", HEATMAP_PATH, err),
+    }
+}
+
+// What save_state and session_save read the current game from.
+#[derive(SystemParam)]
+struct SaveSource<'w, 's> {
+    segments: Res<'w, SnakeSegments>,
+    score: Res<'w, Score>,
+    charges: Res<'w, TeleportCharges>,
+    arena: Res<'w, ArenaSize>,
+    heads: Query<'w, 's, &'static SnakeHead>,
+    positions: Query<'w, 's, &'static Position>,
+    food: Query<
+        'w,
+        's,
+        (
+            &'static Position,
+            Option<&'static FreezeFood>,
+            Option<&'static SmartFood>,
+        ),
+        With<Food>,
+    >,
+    walls: Query<'w, 's, &'static Position, (With<Wall>, Without<BorderTile>)>,
+    config: ConfigSource<'w, 's>,
+}
+
+// The settings a save records; see SaveConfig.
+#[derive(SystemParam)]
+struct ConfigSource<'w, 's> {
+    food_bias: Res<'w, FoodBias>,
+    food_count: Res<'w, FoodCount>,
+    tick_score: Res<'w, SurvivalScorePerTick>,
+    walls_per_food: Res<'w, WallsPerFood>,
+    border: Res<'w, BorderWall>,
+    game_speed: Res<'w, GameSpeed>,
+    movement: Res<'w, MovementTimer>,
+    food_random: Res<'w, FoodRandom>,
+    #[system_param(ignore)]
+    marker: PhantomData<&'s ()>,
+}
+
+impl<'w, 's> ConfigSource<'w, 's> {
+    fn save_config(&self) -> SaveConfig {
+        SaveConfig {
+            food_bias: *self.food_bias,
+            food_count: **self.food_count,
+            tick_score: self.tick_score.0,
+            walls_per_food: self.walls_per_food.0,
+            border: self.border.thickness,
+            game_speed: self.game_speed.0,
+            step_secs: self.movement.timer.duration().as_secs_f32(),
+            food_seed: self.food_random.0.seed_state(),
+        }
+    }
+}
+
+// Where load_state puts a save's settings back.
+#[derive(SystemParam)]
+struct ConfigTarget<'w, 's> {
+    food_bias: ResMut<'w, FoodBias>,
+    food_count: ResMut<'w, FoodCount>,
+    tick_score: ResMut<'w, SurvivalScorePerTick>,
+    walls_per_food: ResMut<'w, WallsPerFood>,
+    border: ResMut<'w, BorderWall>,
+    game_speed: ResMut<'w, GameSpeed>,
+    movement: ResMut<'w, MovementTimer>,
+    food_random: ResMut<'w, FoodRandom>,
+    arena: Res<'w, ArenaSize>,
+    border_tiles: Query<'w, 's, Entity, With<BorderTile>>,
+}
+
+impl<'w, 's> ConfigTarget<'w, 's> {
+    fn restore(&mut self, commands: &mut Commands, config: &SaveConfig) {
+        *self.food_bias = config.food_bias;
+        **self.food_count = config.food_count;
+        self.tick_score.0 = config.tick_score;
+        self.walls_per_food.0 = config.walls_per_food;
+        self.game_speed.0 = config.game_speed;
+        self.movement
+            .timer
+            .set_duration(Duration::from_secs_f32(config.step_secs));
+        if let Some(state) = config.food_seed {
+            self.food_random.0 = Box::new(SeededRandom::resume(state));
+        }
+        if self.border.thickness != config.border {
+            self.border.thickness = config.border;
+            for ent in self.border_tiles.iter() {
+                commands.entity(ent).despawn_recursive();
+            }
+            spawn_border(commands, &self.border, &self.arena);
+        }
+    }
+}
+
+impl<'w, 's> SaveSource<'w, 's> {
+    fn save_game(&self) -> Option<SaveGame> {
+        let direction = self.heads.get(*self.segments.first()?).ok()?.direction;
+        Some(SaveGame {
+            arena_width: self.arena.width,
+            arena_height: self.arena.height,
+            snapshot: GameSnapshot {
+                segments: self
+                    .segments
+                    .iter()
+                    .map(|e| *self.positions.get(*e).unwrap())
+                    .collect(),
+                direction,
+                food: self
+                    .food
+                    .iter()
+                    .map(|(pos, freeze, smart)| SavedFood::new(*pos, freeze, smart))
+                    .collect(),
+                walls: self.walls.iter().copied().collect(),
+            },
+            score: **self.score,
+            teleport_charges: **self.charges,
+            config: Some(self.config.save_config()),
+        })
+    }
+}
+
+fn save_state(
+    keyboard_input: Res<Input<KeyCode>>,
+    state: Res<State<GameState>>,
+    source: SaveSource,
+) {
+    if !keyboard_input.just_pressed(SAVE_KEY) || *state.current() != GameState::Playing {
+        return;
+    }
+    let save = match source.save_game() {
+        Some(save) => save,
+        None => return,
+    };
+    match save.write(SAVE_PATH) {
+        Ok(()) => info!("Saved game to {}", SAVE_PATH),
+        Err(err) => warn!("Could not save game to {}: {}", SAVE_PATH, err),
+    }
+}
+
+fn session_save(
+    mut tick_reader: EventReader<TickEvent>,
+    session: Res<SessionFile>,
+    source: SaveSource,
+) {
+    let due = tick_reader
+        .iter()
+        .any(|TickEvent(tick)| tick % SESSION_SAVE_TICKS == 0);
+    if let Some(save) = source.save_game().filter(|_| due) {
+        if let Err(err) = save.write(&session.0) {
+            warn!("Could not save session to {}: {}", session.0, err);
+        }
+    }
+}
+
+fn find_session(
+    session: Res<SessionFile>,
+    arena: Res<ArenaSize>,
+    border: Res<BorderWall>,
+    mut pending: ResMut<PendingSession>,
+) {
+    if !std::path::Path::new(&session.0).exists() {
+        return;
+    }
+    // A session cut off mid-write or from a bigger arena is not worth resuming.
+    match SaveGame::read(&session.0, &arena, &border) {
+        Ok(save) => pending.0 = Some(save),
+        Err(err) => warn!("Ignoring session in {}: {}", session.0, err),
+    }
+}
+
+fn load_state(
+    mut commands: Commands,
+    keyboard_input: Res<Input<KeyCode>>,
+    state: Res<State<GameState>>,
+    mut segments: ResMut<SnakeSegments>,
+    mut score: ResMut<Score>,
+    mut charges: ResMut<TeleportCharges>,
+    mut history: ResMut<RewindHistory>,
+    mut config: ConfigTarget,
+    look: SnakeLook,
+    food_timeout: Res<FoodTimeout>,
+    mut pending: ResMut<PendingSession>,
+    session: Res<SessionFile>,
+    mut inputs: Query<&mut InputBuffer>,
+    food: Query<Entity, With<Food>>,
+    segment_entities: Query<Entity, With<SnakeSegment>>,
+    walls: Query<Entity, (With<Wall>, Without<BorderTile>)>,
+) {
+    if *state.current() != GameState::Playing {
+        return;
+    }
+    let (save, source) = if keyboard_input.just_pressed(LOAD_KEY) {
+        match SaveGame::read(SAVE_PATH, &config.arena, &config.border) {
+            Ok(save) => (save, SAVE_PATH),
+            Err(err) => {
+                warn!("Could not load game from {}: {}", SAVE_PATH, err);
+                return;
+            }
+        }
+    } else if keyboard_input.just_pressed(RESUME_KEY) {
+        match pending.0.take() {
+            Some(save) => (save, session.0.as_str()),
+            None => return,
+        }
+    } else {
+        return;
+    };
+
+    for ent in food.iter().chain(segment_entities.iter()) {
+        commands.entity(ent).despawn_recursive();
+    }
+
+    if let Some(saved) = &save.config {
+        config.restore(&mut commands, saved);
+    }
+    let snapshot = save.snapshot;
+    *segments = SnakeSegments(spawn_snake(
+        &mut commands,
+        &snapshot.segments,
+        snapshot.direction,
+        &look,
+    ));
+    for food in &snapshot.food {
+        spawn_saved_food(&mut commands, food, &food_timeout);
+    }
+    restore_walls(&mut commands, walls.iter(), &snapshot);
+
+    **score = save.score;
+    **charges = save.teleport_charges;
+    history.clear();
+    if let Some(mut input_buffer) = inputs.iter_mut().next() {
+        input_buffer.inputs.clear();
+    }
+    info!("Loaded game from {}", source);
+}
+
+fn snake_rewind(
+    mut commands: Commands,
+    keyboard_input: Res<Input<KeyCode>>,
+    state: Res<State<GameState>>,
+    mut history: ResMut<RewindHistory>,
+    mut segments: ResMut<SnakeSegments>,
+    look: SnakeLook,
+    food_timeout: Res<FoodTimeout>,
+    mut heads: Query<&mut SnakeHead>,
+    mut positions: Query<&mut Position>,
+    mut inputs: Query<&mut InputBuffer>,
+    food: Query<Entity, With<Food>>,
+    walls: Query<Entity, (With<Wall>, Without<BorderTile>)>,
+) {
+    if !keyboard_input.just_pressed(REWIND_KEY) || *state.current() != GameState::Playing {
+        return;
+    }
+
+    let keep = history.len().saturating_sub(REWIND_TICKS);
+    let snapshot = match history.drain(keep..).next() {
+        Some(snapshot) => snapshot,
+        None => return,
+    };
+
+    // A back head stays at the back whatever the length, so only the body in between changes.
+    let back = look.two_headed.0.then(|| segments.pop()).flatten();
+    let body_len = snapshot.segments.len() - usize::from(back.is_some());
+    if segments.len() > body_len {
+        for ent in segments.drain(body_len..) {
+            commands.entity(ent).despawn_recursive();
+        }
+    }
+    for (i, pos) in snapshot.segments[..body_len].iter().enumerate() {
+        if i < segments.len() {
+            *positions.get_mut(segments[i]).unwrap() = *pos;
+        } else {
+            let segment = spawn_segment(&mut commands, *pos, &look);
+            segments.push(segment);
+        }
+    }
+    if let Some(back) = back {
+        *positions.get_mut(back).unwrap() = snapshot.segments[body_len];
+        segments.push(back);
+        if let (Ok(mut head), Some(direction)) =
+            (heads.get_mut(back), back_direction(&snapshot.segments))
+        {
+            head.direction = direction;
+        }
+    }
+
+    if let Some(mut head) = segments.first().and_then(|e| heads.get_mut(*e).ok()) {
+        head.direction = snapshot.direction;
+    }
+
+    for ent in food.iter() {
+        commands.entity(ent).despawn_recursive();
+    }
+    for food in &snapshot.food {
+        spawn_saved_food(&mut commands, food, &food_timeout);
+    }
+    restore_walls(&mut commands, walls.iter(), &snapshot);
+
+    if let Some(mut input_buffer) = inputs.iter_mut().next() {
+        input_buffer.inputs.clear();
+    }
+}
+
+// The per-round state game_over sets back once the board is cleared.
+#[derive(SystemParam)]
+struct RoundReset<'w, 's> {
+    history: ResMut<'w, RewindHistory>,
+    tick: ResMut<'w, TickCount>,
+    score: ResMut<'w, Score>,
+    teleport_charges: ResMut<'w, TeleportCharges>,
+    reverse_charges: ResMut<'w, ReverseCharges>,
+    speedrun: ResMut<'w, Speedrun>,
+    grace_ticks: Res<'w, GraceTicks>,
+    grace: ResMut<'w, StartGrace>,
+    first_food: ResMut<'w, FirstFood>,
+    heatmap_per_run: Res<'w, HeatmapPerRun>,
+    heatmap: ResMut<'w, Heatmap>,
+    arena: ResMut<'w, ArenaSize>,
+    hunger: ResMut<'w, Hunger>,
+    pending: ResMut<'w, PendingSession>,
+    session: Res<'w, SessionFile>,
+    #[system_param(ignore)]
+    marker: PhantomData<&'s ()>,
+}
+
+impl<'w, 's> RoundReset<'w, 's> {
+    fn reset(&mut self) {
+        self.history.clear();
+        *self.tick = TickCount::default();
+        *self.score = Score::default();
+        *self.teleport_charges = TeleportCharges::default();
+        *self.reverse_charges = ReverseCharges::default();
+        self.speedrun.reset();
+        **self.grace = self.grace_ticks.0;
+        self.first_food.0 = true;
+        if self.heatmap_per_run.0 {
+            *self.heatmap = Heatmap::new(&self.arena);
+        }
+        if let Some(timer) = self.hunger.0.as_mut() {
+            timer.reset();
+        }
+        // The heatmap above is sized for the arena as it was; arena_relayout shrinks it with the
+        // rest of the edge layout.
+        if *self.arena != ArenaSize::default() {
+            *self.arena = ArenaSize::default();
+        }
+        // A round that ends normally leaves nothing to recover.
+        if let Err(err) = std::fs::remove_file(&self.session.0) {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                warn!("Could not remove {}: {}", self.session.0, err);
+            }
+        }
+        if self.pending.0.is_some() {
+            self.pending.0 = None;
+        }
+    }
+}
+
+fn game_over(
+    mut commands: Commands,
+    mut reader: EventReader<GameOverEvent>,
+    food_writer: EventWriter<FoodEvent>,
+    segments_res: ResMut<SnakeSegments>,
+    mut round: RoundReset,
+    setup: RoundSetup,
+    mut inputs: Query<&mut InputBuffer>,
+    board: Query<Entity, (Or<(With<Food>, With<Wall>)>, Without<BorderTile>)>,
+    segments: Query<Entity, With<SnakeSegment>>,
+) {
+    if reader.iter().next().is_some() {
+        for ent in board.iter().chain(segments.iter()) {
+            commands.entity(ent).despawn_recursive();
+        }
+
+        setup_snake_game(commands, food_writer, segments_res, setup);
+        round.reset();
+
+        if let Some(mut input_buffer) = inputs.iter_mut().next() {
+            input_buffer.inputs.clear();
+        }
+    }
+}
+
+fn snake_eating(
+    mut commands: Commands,
+    mut growth_writer: EventWriter<GrowthEvent>,
+    mut food_writer: EventWriter<FoodEvent>,
+    mut score: ResMut<Score>,
+    walls_per_food: Res<WallsPerFood>,
+    mut freeze: ResMut<MovementFreeze>,
+    arena: Res<ArenaSize>,
+    windows: Res<Windows>,
+    view_mode: Res<ViewMode>,
+    font: Res<UiFont>,
+    food_positions: Query<(Entity, &Position, Option<&FreezeFood>), With<Food>>,
+    heads: Query<(&Position, &SnakeHead)>,
+    occupied: Query<&Position, Or<(With<SnakeSegment>, With<Food>, With<Wall>)>>,
+) {
+    let window = windows.get_primary().unwrap();
+    let mut eaten = 0;
+    for (head_pos, _) in heads.iter() {
+        for (ent, food_pos, freeze_food) in food_positions.iter() {
+            if food_pos == head_pos {
+                if freeze_food.is_some() {
+                    freeze.0 = Some(Timer::from_seconds(FREEZE_SECS, false));
+                }
+                commands.entity(ent).despawn_recursive();
+                growth_writer.send(GrowthEvent);
+                food_writer.send(FoodEvent);
+                **score += FOOD_SCORE;
+                let translation = position_to_translation(food_pos, window, &arena)
+                    - view_offset(&view_mode, Some(head_pos), window, &arena);
+                spawn_score_popup(&mut commands, &font, translation, FOOD_SCORE);
+                eaten += 1;
+            }
+        }
+    }
+
+    let mut blocked = occupied.iter().copied().collect::<Vec<Position>>();
+    // A wall right in front of the head would end the round with no chance to turn.
+    blocked.extend(heads.iter().map(|(pos, head)| pos.step(head.direction)));
+    for _ in 0..eaten * walls_per_food.0 {
+        let free = free_cells(&arena, &blocked);
+        if free.is_empty() {
+            break;
+        }
+        let position = free[(random::<f32>() * free.len() as f32) as usize];
+        blocked.push(position);
+        spawn_wall(&mut commands, position);
+    }
+}
+
+fn free_cells(arena: &ArenaSize, occupied: &[Position]) -> Vec<Position> {
+    arena
+        .cells()
+        .filter(|cell| !occupied.contains(cell))
+        .collect()
+}
+
+fn smart_food_flee(
+    arena: Res<ArenaSize>,
+    heads: Query<&Position, (With<SnakeHead>, Without<SmartFood>)>,
+    blockers: Query<
+        &Position,
+        (
+            Or<(With<SnakeSegment>, With<Food>, With<Wall>)>,
+            Without<SmartFood>,
+        ),
+    >,
+    mut smart_food: Query<(&mut Position, &mut SmartFood)>,
+) {
+    let head = match heads.iter().next() {
+        Some(head) => *head,
+        None => return,
+    };
+    let distance = |pos: Position| (pos.x - head.x).abs() + (pos.y - head.y).abs();
+    let mut blocked = blockers
+        .iter()
+        .chain(smart_food.iter().map(|(pos, _)| pos))
+        .copied()
+        .collect::<Vec<Position>>();
+    for (mut pos, mut smart) in smart_food.iter_mut() {
+        if smart.moves_left == 0 {
+            continue;
+        }
+        let flee = [
+            Direction::Left,
+            Direction::Right,
+            Direction::Up,
+            Direction::Down,
+        ]
+        .into_iter()
+        .map(|direction| pos.step(direction))
+        .filter(|cell| cell.in_arena(&arena) && !blocked.contains(cell))
+        .max_by_key(|cell| distance(*cell))
+        .filter(|cell| distance(*cell) > distance(*pos));
+        if let Some(cell) = flee {
+            blocked.retain(|taken| *taken != *pos);
+            blocked.push(cell);
+            *pos = cell;
+            smart.moves_left -= 1;
+        }
+    }
+}
+
+fn spawn_score_popup(commands: &mut Commands, font: &UiFont, translation: Vec3, amount: u32) {
+    spawn_floating_text(commands, font, format!("+{}", amount), translation);
+}
+
+fn spawn_floating_text(commands: &mut Commands, font: &UiFont, value: String, translation: Vec3) {
+    commands
+        .spawn_bundle(Text2dBundle {
+            text: Text::with_section(
+                value,
+                TextStyle {
+                    font: font.0.clone(),
+                    font_size: POPUP_FONT_SIZE,
+                    color: POPUP_COLOR,
+                },
+                TextAlignment {
+                    vertical: VerticalAlign::Center,
+                    horizontal: HorizontalAlign::Center,
+                },
+            ),
+            transform: Transform::from_translation(translation + Vec3::Z * ZLayer::Popup.z()),
+            ..default()
+        })
+        .insert(FloatingText {
+            timer: Timer::from_seconds(POPUP_SECS, false),
+            velocity: Vec3::new(0.0, POPUP_RISE_SPEED, 0.0),
+        });
+}
+
+fn milestone_popup(
+    mut commands: Commands,
+    mut milestone_reader: EventReader<MilestoneEvent>,
+    windows: Res<Windows>,
+    arena: Res<ArenaSize>,
+    view_mode: Res<ViewMode>,
+    font: Res<UiFont>,
+    heads: Query<&Position, With<SnakeHead>>,
+) {
+    let window = windows.get_primary().unwrap();
+    for MilestoneEvent(length) in milestone_reader.iter() {
+        if let Some(head_pos) = heads.iter().next() {
+            spawn_floating_text(
+                &mut commands,
+                &font,
+                format!("Length {}!", length),
+                position_to_translation(head_pos, window, &arena)
+                    - view_offset(&view_mode, Some(head_pos), window, &arena),
+            );
+        }
+    }
+}
+
+fn floating_text(
+    mut commands: Commands,
+    clock: Res<GameClock>,
+    reduced_motion: Res<ReducedMotion>,
+    mut query: Query<(Entity, &mut FloatingText, &mut Transform, &mut Text)>,
+) {
+    for (ent, mut floating, mut transform, mut text) in query.iter_mut() {
+        floating.timer.tick(clock.delta);
+        if floating.timer.finished() {
+            commands.entity(ent).despawn();
+            continue;
+        }
+        if reduced_motion.0 {
+            continue;
+        }
+        transform.translation += floating.velocity * clock.delta.as_secs_f32();
+        let alpha = floating.timer.percent_left();
+        for section in text.sections.iter_mut() {
+            section.style.color.set_a(alpha);
+        }
+    }
+}
+
+fn food_lifetime(
+    mut commands: Commands,
+    time: Res<Time>,
+    speed: Res<GameSpeed>,
+    mut food_writer: EventWriter<FoodEvent>,
+    mut food: Query<(Entity, &mut FoodLifetime)>,
+) {
+    for (ent, mut lifetime) in food.iter_mut() {
+        if lifetime.0.tick(speed.scale(time.delta())).just_finished() {
+            commands.entity(ent).despawn_recursive();
+            food_writer.send(FoodEvent);
+        }
+    }
+}
+
+fn snake_hunger(
+    mut commands: Commands,
+    time: Res<Time>,
+    speed: Res<GameSpeed>,
+    mut hunger: ResMut<Hunger>,
+    two_headed: Res<TwoHeaded>,
+    mut segments: ResMut<SnakeSegments>,
+    mut growth_reader: EventReader<GrowthEvent>,
+    mut game_over_writer: EventWriter<GameOverEvent>,
+    mut heads: Query<&mut SnakeHead>,
+    mut positions: Query<&mut Position>,
+) {
+    let timer = match hunger.0.as_mut() {
+        Some(timer) => timer,
+        None => return,
+    };
+    if growth_reader.iter().next().is_some() {
+        timer.reset();
+        return;
+    }
+    if !timer.tick(speed.scale(time.delta())).just_finished() {
+        return;
+    }
+    if segments.len() <= 1 + usize::from(two_headed.0) {
+        game_over_writer.send(GameOverEvent);
+        return;
+    }
+    // A back head isn't trimmed off; it moves onto the segment in front of it instead.
+    let back = two_headed.0.then(|| segments.pop()).flatten();
+    if let Some(tail) = segments.pop() {
+        if let Some(back) = back {
+            let tail_pos = *positions.get(tail).unwrap();
+            *positions.get_mut(back).unwrap() = tail_pos;
+            segments.push(back);
+            let cells = segments
+                .iter()
+                .map(|e| *positions.get(*e).unwrap())
+                .collect::<Vec<Position>>();
+            if let (Ok(mut head), Some(direction)) = (heads.get_mut(back), back_direction(&cells)) {
+                head.direction = direction;
+            }
+        }
+        commands.entity(tail).despawn_recursive();
+    }
+}
+
+fn snake_growth(
+    mut commands: Commands,
+    last_tail_position: Res<LastTailPosition>,
+    mut segments: ResMut<SnakeSegments>,
+    mut growth_reader: EventReader<GrowthEvent>,
+    mut milestone_writer: EventWriter<MilestoneEvent>,
+    milestones: Res<Milestones>,
+    anchor: Res<GrowthAnchor>,
+    look: SnakeLook,
+    mut positions: Query<&mut Position>,
+) {
+    // Several foods can be eaten in one tick, so every pending event adds a segment.
+    let growth = growth_reader.iter().count();
+    if growth > 0 {
+        let old_len = segments.len() as u32;
+        let last_tail = last_tail_position.0.unwrap();
+        // Two heads leave no tail to grow from, so the new segments go in the middle.
+        let insert_at = match *anchor {
+            _ if look.two_headed.0 => Some((segments.len() / 2).max(1)),
+            GrowthAnchor::Head => Some(1),
+            GrowthAnchor::Tail => None,
+        };
+        match insert_at {
+            None => {
+                // Extra segments stack on the vacated tail cell and unfold over the next ticks.
+                for _ in 0..growth {
+                    segments.push(spawn_segment(&mut commands, last_tail, &look));
+                }
+            }
+            Some(at) => {
+                // The occupied cells are the same as for tail growth, but the new entities sit
+                // at `at` and every older segment behind them steps back to where it was before
+                // this tick.
+                let mut cells = segments
+                    .iter()
+                    .map(|e| *positions.get(*e).unwrap())
+                    .collect::<Vec<Position>>();
+                cells.extend(std::iter::repeat_n(last_tail, growth));
+
+                for (i, cell) in cells.iter().enumerate().take(at + growth).skip(at) {
+                    let segment = spawn_segment(&mut commands, *cell, &look);
+                    segments.insert(i, segment);
+                }
+                for (ent, cell) in segments.iter().zip(cells.iter()).skip(at + growth) {
+                    *positions.get_mut(*ent).unwrap() = *cell;
+                }
+            }
+        }
+
+        let new_len = segments.len() as u32;
+        for milestone in milestones.iter() {
+            if old_len < *milestone && *milestone <= new_len {
+                milestone_writer.send(MilestoneEvent(*milestone));
+            }
+        }
+    }
+}
+
+fn tongue_flick(
+    clock: Res<GameClock>,
+    heads: Query<&SnakeHead>,
+    mut tongues: Query<(&Parent, &mut Tongue, &mut Transform, &mut Visibility)>,
+) {
+    for (parent, mut tongue, mut transform, mut visibility) in tongues.iter_mut() {
+        let direction = match heads.get(**parent) {
+            Ok(head) => head.direction,
+            Err(_) => continue,
+        };
+        tongue.timer.tick(clock.delta);
+        let progress = tongue.timer.elapsed_secs() / TONGUE_FLICK_SECS;
+        visibility.is_visible = progress < 1.0;
+        if !visibility.is_visible {
+            continue;
+        }
+
+        // The tongue lives in the head's local space, where the head spans -0.5..0.5.
+        let length = (progress * std::f32::consts::PI).sin() * TONGUE_LENGTH;
+        let reach = 0.5 + length / 2.0;
+        let (translation, scale) = match direction {
+            Direction::Left => (
+                Vec3::new(-reach, 0.0, 0.0),
+                Vec3::new(length, TONGUE_THICKNESS, 1.0),
+            ),
+            Direction::Right => (
+                Vec3::new(reach, 0.0, 0.0),
+                Vec3::new(length, TONGUE_THICKNESS, 1.0),
+            ),
+            Direction::Up => (
+                Vec3::new(0.0, reach, 0.0),
+                Vec3::new(TONGUE_THICKNESS, length, 1.0),
+            ),
+            Direction::Down => (
+                Vec3::new(0.0, -reach, 0.0),
+                Vec3::new(TONGUE_THICKNESS, length, 1.0),
+            ),
+        };
+        transform.translation = translation;
+        transform.scale = scale;
+    }
+}
+
+fn follow_camera(
+    camera_mode: Res<CameraMode>,
+    view_mode: Res<ViewMode>,
+    arena: Res<ArenaSize>,
+    windows: Res<Windows>,
+    heads: Query<&Position, With<SnakeHead>>,
+    mut cameras: Query<&mut Transform, With<MainCamera>>,
+) {
+    if *camera_mode != CameraMode::FollowHead {
+        return;
+    }
+    let window = windows.get_primary().unwrap();
+    if is_minimized(window) {
+        return;
+    }
+    let head = match heads.iter().next() {
+        Some(head) => head,
+        None => return,
+    };
+    let target = position_to_translation(head, window, &arena)
+        - view_offset(&view_mode, Some(head), window, &arena);
+    for mut transform in cameras.iter_mut() {
+        transform.translation.x = target.x;
+        transform.translation.y = target.y;
+    }
+}
+
+fn size_scaling(
+    windows: Res<Windows>,
+    arena: Res<ArenaSize>,
+    padding: Res<CellPadding>,
+    mut query: Query<(&Size, Option<&ZLayer>, &mut Transform)>,
+) {
+    let window = windows.get_primary().unwrap();
+    if is_minimized(window) {
+        return;
+    }
+    for (size, layer, mut transform) in query.iter_mut() {
+        let padded = matches!(layer, Some(ZLayer::Head | ZLayer::Body | ZLayer::Food));
+        let (width, height) = match padding.0.filter(|_| padded) {
+            Some(padding) => (1.0 - padding, 1.0 - padding),
+            None => (size.width, size.height),
+        };
+        transform.scale = Vec3::new(
+            width / arena.width as f32 * window.width(),
+            height / arena.height as f32 * window.height(),
+            1.0,
+        );
+    }
+}
+
+fn spawn_anim(
+    mut commands: Commands,
+    clock: Res<GameClock>,
+    reduced_motion: Res<ReducedMotion>,
+    mut query: Query<(Entity, &mut SpawnAnim, &mut Transform)>,
+) {
+    for (ent, mut anim, mut transform) in query.iter_mut() {
+        anim.timer.tick(clock.delta);
+        if anim.timer.finished() || reduced_motion.0 {
+            commands.entity(ent).remove::<SpawnAnim>();
+            continue;
+        }
+        // Scales on top of the base size set by size_scaling each frame.
+        let scale = anim.timer.percent();
+        transform.scale.x *= scale;
+        transform.scale.y *= scale;
+    }
+}
+
+// Runs in Last so sprites spawned anywhere in the frame are recolored before they're drawn.
+fn apply_theme(
+    theme: Res<Theme>,
+    mut sprites: Query<
+        (
+            &ZLayer,
+            &mut Sprite,
+            Option<&FreezeFood>,
+            Option<&SmartFood>,
+        ),
+        Added<ZLayer>,
+    >,
+) {
+    for (layer, mut sprite, freeze, smart) in sprites.iter_mut() {
+        if freeze.is_some() {
+            sprite.color = theme.freeze_food;
+        } else if smart.is_some() {
+            sprite.color = theme.smart_food;
+        } else if let Some(color) = theme.color(*layer) {
+            sprite.color = color;
+        }
+    }
+}
+
+// Food and walls have no outline of their own, so they get the theme's as they appear; the
+// snake's is drawn by spawn_outline from the Outline resource.
+fn outline_board(
+    mut commands: Commands,
+    theme: Res<Theme>,
+    added: Query<(Entity, &ZLayer), Added<ZLayer>>,
+) {
+    if theme.outline.thickness <= 0.0 {
+        return;
+    }
+    for (ent, layer) in added.iter() {
+        if !matches!(layer, ZLayer::Food | ZLayer::Wall) {
+            continue;
+        }
+        commands.entity(ent).with_children(|parent| {
+            parent.spawn_bundle(SpriteBundle {
+                sprite: Sprite {
+                    color: theme.outline.color,
+                    ..default()
+                },
+                transform: Transform {
+                    translation: Vec3::new(0.0, 0.0, -0.5),
+                    scale: Vec3::splat(1.0 + theme.outline.thickness),
+                    ..default()
+                },
+                ..default()
+            });
+        });
+    }
+}
+
+// Once the pace has changed during a round, tints the snake from calm blue to fast red by the
+// effective step time. Dying clears the tint until the pace changes again.
+fn color_by_speed(
+    enabled: Res<SpeedColors>,
+    theme: Res<Theme>,
+    reduced_motion: Res<ReducedMotion>,
+    movement: Res<MovementTimer>,
+    speed: Res<GameSpeed>,
+    mut game_over_reader: EventReader<GameOverEvent>,
+    mut round_start: Local<Option<f32>>,
+    mut sprites: Query<(&mut Sprite, Option<&SnakeHead>), With<SnakeSegment>>,
+    mut texts: Query<&mut Text, With<PaceText>>,
+) {
+    if !enabled.0 {
+        return;
+    }
+    let step = movement.timer.duration().as_secs_f32() / speed.0;
+    if game_over_reader.iter().next().is_some() {
+        *round_start = Some(step);
+    }
+    let start = *round_start.get_or_insert(step);
+    let active = (step - start).abs() > f32::EPSILON;
+    // 0.0 at the slowest step, 1.0 at the fastest, on a log scale so every speed-up counts alike.
+    let pace = ((MAX_STEP_SECS.ln() - step.ln()) / (MAX_STEP_SECS.ln() - MIN_STEP_SECS.ln()))
+        .clamp(0.0, 1.0);
+
+    // With reduced motion the snake keeps its colors and the HUD shows the pace instead.
+    let label = if active && reduced_motion.0 {
+        format!("Pace: {:.0}%", pace * 100.0)
+    } else {
+        String::new()
+    };
+    for mut text in texts.iter_mut() {
+        if text.sections[0].value != label {
+            text.sections[0].value = label.clone();
+        }
+    }
+
+    let hue = CALM_HUE + (FAST_HUE - CALM_HUE) * pace;
+    for (mut sprite, head) in sprites.iter_mut() {
+        sprite.color = match (active && !reduced_motion.0, head.is_some()) {
+            (true, true) => Color::hsl(hue, 0.8, 0.65),
+            (true, false) => Color::hsl(hue, 0.8, 0.45),
+            (false, true) => theme.head,
+            (false, false) => theme.body,
+        };
+    }
+}
+
+fn segment_spacing(
+    render_every: Res<RenderEvery>,
+    segments: Res<SnakeSegments>,
+    children: Query<&Children, With<SnakeSegment>>,
+    mut visibilities: Query<&mut Visibility, Without<Tongue>>,
+) {
+    if **render_every <= 1 {
+        return;
+    }
+    for (i, segment) in segments.iter().enumerate() {
+        // Visibility doesn't carry over to children, so the outline is hidden along with it;
+        // the tongue is left to tongue_flick.
+        let is_visible = i % **render_every == 0;
+        let outline = children
+            .get(*segment)
+            .into_iter()
+            .flat_map(|children| children.iter());
+        for ent in std::iter::once(segment).chain(outline) {
+            if let Ok(mut visibility) = visibilities.get_mut(*ent) {
+                if visibility.is_visible != is_visible {
+                    visibility.is_visible = is_visible;
+                }
+            }
+        }
+    }
+}
+
+// A minimized window can report a zero or sub-pixel size, which would collapse every sprite;
+// transforms keep their last values until it is restored and the next frame recomputes them.
+fn is_minimized(window: &Window) -> bool {
+    !(window.width() >= 1.0 && window.height() >= 1.0)
+}
+
+// In scrolling mode the whole arena is shifted by this so the head sits at the screen center.
+// Anything placed by arena position, like popups, has to subtract it too.
+fn view_offset(
+    view_mode: &ViewMode,
+    head: Option<&Position>,
+    window: &Window,
+    arena: &ArenaSize,
+) -> Vec3 {
+    match view_mode {
+        ViewMode::Fixed => Vec3::ZERO,
+        ViewMode::Scrolling => head
+            .map(|pos| position_to_translation(pos, window, arena))
+            .unwrap_or(Vec3::ZERO),
+    }
+}
+
+fn position_to_translation(pos: &Position, window: &Window, arena: &ArenaSize) -> Vec3 {
+    fn convert(pos: f32, bound_window: f32, bound_game: f32) -> f32 {
+        let tile_size = bound_window / bound_game;
+        pos / bound_game * bound_window - (bound_window / 2.0) + (tile_size / 2.0)
+    }
+    Vec3::new(
+        convert(pos.x as f32, window.width(), arena.width as f32),
+        convert(pos.y as f32, window.height(), arena.height as f32),
+        0.0,
+    )
+}
+
+fn position_translation(
+    windows: Res<Windows>,
+    arena: Res<ArenaSize>,
+    view_mode: Res<ViewMode>,
+    heads: Query<&Position, With<SnakeHead>>,
+    mut query: Query<(&Position, Option<&ZLayer>, &mut Transform)>,
+) {
+    let window = windows.get_primary().unwrap();
+    if is_minimized(window) {
+        return;
+    }
+    let offset = view_offset(&view_mode, heads.iter().next(), window, &arena);
+    for (pos, layer, mut transform) in query.iter_mut() {
+        transform.translation = position_to_translation(pos, window, &arena) - offset;
+        transform.translation.z = layer.map_or(0.0, |layer| layer.z());
+    }
+}
+
+// The settings food_spawner runs by, grouped like MovementRules.
+#[derive(SystemParam)]
+struct FoodRules<'w, 's> {
+    count: Res<'w, FoodCount>,
+    timeout: Res<'w, FoodTimeout>,
+    bias: Res<'w, FoodBias>,
+    reachable: Res<'w, ReachableFood>,
+    freeze_chance: Res<'w, FreezeFoodChance>,
+    smart_food: Res<'w, SmartFoodConfig>,
+    after_grace: Res<'w, FoodAfterGrace>,
+    first_radius: Res<'w, FirstFoodRadius>,
+    arena: Res<'w, ArenaSize>,
+    #[system_param(ignore)]
+    marker: PhantomData<&'s ()>,
+}
+
+fn food_spawner(
+    mut commands: Commands,
+    rules: FoodRules,
+    mut initial_food: ResMut<InitialFood>,
+    mut first_food: ResMut<FirstFood>,
+    mut food_sequence: ResMut<FoodSequence>,
+    mut food_random: ResMut<FoodRandom>,
+    grace: Res<StartGrace>,
+    query: Query<&Position, With<SnakeSegment>>,
+    heads: Query<(&Position, &SnakeHead)>,
+    food: Query<&Position, With<Food>>,
+    walls: Query<&Position, With<Wall>>,
+    mut recent_spawns: ResMut<RecentFoodSpawns>,
+    mut food_reader: EventReader<FoodEvent>,
+    mut game_over_writer: EventWriter<GameOverEvent>,
+) {
+    // Restarting can queue more than one FoodEvent for the same board, so never spawn past
+    // FoodCount.
+    let requested = food_reader.iter().count();
+    // release_food asks again once the grace period is over.
+    if rules.after_grace.0 && **grace > 0 {
+        return;
+    }
+    let room = rules.count.saturating_sub(food.iter().count());
+    // Food never stacks: cells taken by the snake, by other food, and by food placed earlier in
+    // this batch (not yet visible to the query) are all off limits.
+    let mut occupied = query
+        .iter()
+        .chain(food.iter())
+        .chain(walls.iter())
+        .copied()
+        .collect::<Vec<Position>>();
+    if rules.reachable.0 && requested > 0 {
+        if let Some((head, _)) = heads.iter().next() {
+            // Cells the head can't get to are treated as taken.
+            let obstacles = query
+                .iter()
+                .chain(walls.iter())
+                .copied()
+                .collect::<Vec<_>>();
+            let reachable = reachable_cells(&rules.arena, *head, &obstacles);
+            let unreachable = free_cells(&rules.arena, &occupied)
+                .into_iter()
+                .filter(|cell| !reachable.contains(cell))
+                .collect::<Vec<_>>();
+            occupied.extend(unreachable);
+        }
+    }
+    for _ in 0..requested.min(room) {
+        if free_cells(&rules.arena, &occupied).is_empty() {
+            // Nowhere left to put food, so the round is over.
+            info!("No free cell left for food");
+            game_over_writer.send(GameOverEvent);
+            break;
+        }
+        let heading = match *rules.bias {
+            FoodBias::None => None,
+            FoodBias::AvoidAhead => heads
+                .iter()
+                .next()
+                .map(|(pos, head)| (*pos, head.direction)),
+        };
+        let initial = initial_food.0.take().filter(|pos| {
+            let free = !occupied.contains(pos);
+            if !free {
+                warn!("Initial food at {:?} is taken, placing it elsewhere", pos);
+            }
+            free
+        });
+        let nearby = match (rules.first_radius.0, heads.iter().next()) {
+            (Some(radius), Some((head, _))) if first_food.0 => {
+                let cells = free_cells(&rules.arena, &occupied)
+                    .into_iter()
+                    .filter(|cell| {
+                        (cell.x - head.x).unsigned_abs() + (cell.y - head.y).unsigned_abs()
+                            <= radius
+                    })
+                    .collect::<Vec<Position>>();
+                // With no free cell in range, the food goes anywhere as usual.
+                (!cells.is_empty()).then(|| food_random.0.next_free_cell(&cells))
+            }
+            _ => None,
+        };
+        first_food.0 = false;
+        let position = initial.or(nearby).unwrap_or_else(|| {
+            next_food_position(
+                &rules.arena,
+                &mut food_sequence,
+                food_random.0.as_mut(),
+                &occupied,
+                heading,
+            )
+        });
+        occupied.push(position);
+        if recent_spawns.len() == SPAWN_MARKER_COUNT {
+            recent_spawns.pop_front();
+        }
+        recent_spawns.push_back(position);
+        let food = spawn_food(&mut commands, position, &rules.timeout);
+        if food_random.0.roll() < rules.freeze_chance.0 {
+            commands.entity(food).insert(FreezeFood);
+        } else if food_random.0.roll() < rules.smart_food.chance {
+            commands.entity(food).insert(SmartFood {
+                moves_left: rules.smart_food.moves,
+            });
+        }
+    }
+}
+
+// Cells reachable from `start` through the arena without crossing `obstacles`.
+fn reachable_cells(arena: &ArenaSize, start: Position, obstacles: &[Position]) -> Vec<Position> {
+    let mut reachable = vec![start];
+    let mut frontier = VecDeque::from([start]);
+    while let Some(cell) = frontier.pop_front() {
+        for direction in [
+            Direction::Left,
+            Direction::Right,
+            Direction::Up,
+            Direction::Down,
+        ] {
+            let next = cell.step(direction);
+            if next.in_arena(arena) && !obstacles.contains(&next) && !reachable.contains(&next) {
+                reachable.push(next);
+                frontier.push_back(next);
+            }
+        }
+    }
+    reachable
+}
+
+fn next_food_position(
+    arena: &ArenaSize,
+    food_sequence: &mut FoodSequence,
+    random: &mut dyn RandomSource,
+    occupied: &[Position],
+    avoid_ahead_of: Option<(Position, Direction)>,
+) -> Position {
+    while let Some(scripted) = food_sequence.pop_front() {
+        if !scripted.in_arena(arena) {
+            warn!("Skipping scripted food outside the arena at {:?}", scripted);
+            continue;
+        }
+        if occupied.contains(&scripted) {
+            continue;
+        }
+        return scripted;
+    }
+
+    let free = free_cells(arena, occupied);
+    if let Some((head, direction)) = avoid_ahead_of {
+        let candidates = free
+            .iter()
+            .copied()
+            .filter(|cell| !head.is_ahead(direction, *cell))
+            .collect::<Vec<Position>>();
+        if candidates.len() >= FOOD_BIAS_MIN_CELLS {
+            return random.next_free_cell(&candidates);
+        }
+    }
+    random.next_free_cell(&free)
+}
+
+fn has_flag(flag: &str) -> bool {
+    std::env::args().any(|arg| arg == flag)
+}
+
+fn flag_value(flag: &str) -> Option<String> {
+    std::env::args().skip_while(|arg| arg != flag).nth(1)
+}
+
+// Positive seconds that fit in a Duration, so a Timer built from them can't panic.
+fn secs_value(flag: &str) -> Option<f32> {
+    flag_value(flag)
+        .and_then(|secs| secs.parse::<f32>().ok())
+        .filter(|secs| *secs > 0.0 && Duration::try_from_secs_f32(*secs).is_ok())
+}
+
+fn main() {
+    let sprite_style = if has_flag("--circles") {
+        SpriteStyle::Circle
+    } else {
+        SpriteStyle::Square
+    };
+    let view_mode = if has_flag("--scroll") {
+        ViewMode::Scrolling
+    } else {
+        ViewMode::Fixed
+    };
+    let camera_mode = if has_flag("--follow-camera") {
+        CameraMode::FollowHead
+    } else {
+        CameraMode::ArenaCenter
+    };
+    let buffer_mode = if has_flag("--immediate-input") {
+        BufferMode::Immediate
+    } else {
+        BufferMode::Buffered
+    };
+    // A zero or negative lifetime would make food vanish the moment it appears, and one too
+    // long for a Duration would panic when the first food spawns.
+    let food_timeout = FoodTimeout(secs_value("--food-timeout"));
+    let window_config = WindowConfig {
+        title: flag_value("--title").unwrap_or_else(|| WINDOW_TITLE.to_string()),
+        icon_path: flag_value("--icon"),
+    };
+    let speedrun = Speedrun {
+        target: flag_value("--speedrun").and_then(|score| score.parse().ok()),
+        ..default()
+    };
+    let reduced_motion = ReducedMotion(has_flag("--reduced-motion"));
+    let show_tongue = ShowTongue(has_flag("--tongue"));
+    let mut outline = Outline::default();
+    if let Some(thickness) = flag_value("--outline").and_then(|value| value.parse().ok()) {
+        outline.thickness = thickness;
+    }
+    let mut pause_overlay = PauseOverlay::default();
+    if let Some(alpha) = flag_value("--pause-dim").and_then(|alpha| alpha.parse().ok()) {
+        pause_overlay.alpha = alpha;
+    }
+    let grace_ticks = GraceTicks(
+        flag_value("--grace")
+            .and_then(|ticks| ticks.parse().ok())
+            .unwrap_or(0),
+    );
+    let food_after_grace = FoodAfterGrace(has_flag("--food-after-grace"));
+    let food_count = flag_value("--food-count")
+        .and_then(|count| count.parse().ok())
+        .map(FoodCount)
+        .unwrap_or_default();
+    let survival_score = SurvivalScorePerTick(
+        flag_value("--tick-score")
+            .and_then(|points| points.parse().ok())
+            .unwrap_or(0),
+    );
+    let exempt_neck = ExemptNeck(has_flag("--exempt-neck"));
+    let lone_head_reverse = LoneHeadReverse(has_flag("--lone-head-reverse"));
+    let shed_on_wall = ShedOnWall(has_flag("--shed"));
+    let render_every = flag_value("--render-every")
+        .and_then(|n| n.parse().ok())
+        .map(RenderEvery)
+        .unwrap_or_default();
+    let cell_padding = CellPadding(
+        flag_value("--padding")
+            .and_then(|padding| padding.parse::<f32>().ok())
+            .filter(|padding| (0.0..=0.9).contains(padding)),
+    );
+    let speed_colors = SpeedColors(has_flag("--speed-colors"));
+    let turbo_tap = TurboTap(has_flag("--turbo-tap"));
+    let turn_smoothing = TurnSmoothing(has_flag("--smooth-turns"));
+    let coalesce_inputs = CoalesceInputs(has_flag("--coalesce-input"));
+    let food_random = flag_value("--food-seed")
+        .and_then(|seed| seed.parse().ok())
+        .map(|seed| FoodRandom(Box::new(SeededRandom::new(seed))))
+        .unwrap_or_default();
+    let max_fps = MaxFps(
+        flag_value("--max-fps")
+            .and_then(|fps| fps.parse::<f32>().ok())
+            .filter(|fps| *fps > 0.0 && Duration::try_from_secs_f32(1.0 / *fps).is_ok()),
+    );
+    let game_speed = flag_value("--game-speed")
+        .and_then(|speed| speed.parse::<f32>().ok())
+        .filter(|speed| *speed > 0.0 && *speed <= MAX_GAME_SPEED)
+        .map(GameSpeed)
+        .unwrap_or_default();
+    let hunger = Hunger(secs_value("--hunger").map(|secs| Timer::from_seconds(secs, true)));
+    // Clamped to MAX_BORDER_THICKNESS by clamp_border_wall once logging is up.
+    let border_wall = BorderWall {
+        thickness: flag_value("--border")
+            .and_then(|thickness| thickness.parse().ok())
+            .unwrap_or(0),
+    };
+    let walls_per_food = WallsPerFood(
+        flag_value("--walls-per-food")
+            .and_then(|walls| walls.parse().ok())
+            .unwrap_or(0),
+    );
+    let debug_mode = DebugMode(has_flag("--debug"));
+    let show_projection = ShowProjection(has_flag("--ghost"));
+    let theme = if has_flag("--high-contrast") {
+        Theme::high_contrast()
+    } else {
+        Theme::default()
+    };
+    // A theme's outline is a minimum; a thicker --outline still wins.
+    if theme.outline.thickness > outline.thickness {
+        outline = theme.outline;
+    }
+    let freeze_chance = FreezeFoodChance(
+        flag_value("--freeze-food")
+            .and_then(|chance| chance.parse().ok())
+            .unwrap_or(0.0),
+    );
+    let mut smart_food = SmartFoodConfig::default();
+    if let Some(chance) = flag_value("--smart-food").and_then(|chance| chance.parse().ok()) {
+        smart_food.chance = chance;
+    }
+    if let Some(moves) = flag_value("--smart-food-moves").and_then(|moves| moves.parse().ok()) {
+        smart_food.moves = moves;
+    }
+    let reachable_food = ReachableFood(has_flag("--reachable-food"));
+    let first_food_radius =
+        FirstFoodRadius(flag_value("--first-food-radius").and_then(|radius| radius.parse().ok()));
+    // Given as "x,y"; anything outside the arena is ignored.
+    let initial_food = InitialFood(
+        flag_value("--initial-food")
+            .and_then(|cell| {
+                let (x, y) = cell.split_once(',')?;
+                Some(Position {
+                    x: x.trim().parse().ok()?,
+                    y: y.trim().parse().ok()?,
+                })
+            })
+            .filter(|pos| pos.in_arena(&ArenaSize::default())),
+    );
+    let food_bias = if has_flag("--food-avoid-ahead") {
+        FoodBias::AvoidAhead
+    } else {
+        FoodBias::None
+    };
+    let heatmap_per_run = HeatmapPerRun(has_flag("--heatmap-per-run"));
+    let ice_coverage = IceCoverage(
+        flag_value("--ice")
+            .and_then(|fraction| fraction.parse().ok())
+            .unwrap_or(0.0),
+    );
+    let arena_growth = ArenaGrowth(has_flag("--grow-arena"));
+    let two_headed = TwoHeaded(has_flag("--two-headed"));
+    let growth_anchor = if has_flag("--grow-from-head") {
+        GrowthAnchor::Head
+    } else {
+        GrowthAnchor::Tail
+    };
+
+    App::new()
+        .insert_resource(ClearColor(theme.clear))
+        .insert_resource(WindowDescriptor {
+            title: window_config.title.clone(),
+            width: WINDOW_WIDTH,
+            height: WINDOW_HEIGHT,
+            ..default()
+        })
+        .insert_resource(window_config)
+        .insert_resource(sprite_style)
+        .insert_resource(view_mode)
+        .insert_resource(camera_mode)
+        .insert_resource(ArenaSize::default())
+        .insert_resource(arena_growth)
+        .insert_resource(growth_anchor)
+        .insert_resource(two_headed)
+        .insert_resource(show_tongue)
+        .insert_resource(outline)
+        .insert_resource(reduced_motion)
+        .insert_resource(buffer_mode)
+        .insert_resource(turn_smoothing)
+        .insert_resource(coalesce_inputs)
+        .insert_resource(turbo_tap)
+        .insert_resource(exempt_neck)
+        .insert_resource(lone_head_reverse)
+        .insert_resource(shed_on_wall)
+        .insert_resource(cell_padding)
+        .insert_resource(render_every)
+        .insert_resource(speed_colors)
+        .insert_resource(survival_score)
+        .insert_resource(food_timeout)
+        .insert_resource(food_bias)
+        .insert_resource(reachable_food)
+        .insert_resource(freeze_chance)
+        .insert_resource(smart_food)
+        .insert_resource(MovementFreeze::default())
+        .insert_resource(theme)
+        .insert_resource(show_projection)
+        .insert_resource(debug_mode)
+        .insert_resource(RecentFoodSpawns::default())
+        .insert_resource(walls_per_food)
+        .insert_resource(border_wall)
+        .insert_resource(hunger)
+        .insert_resource(game_speed)
+        .insert_resource(max_fps)
+        .insert_resource(food_random)
+        .insert_resource(ice_coverage)
+        .insert_resource(pause_overlay)
+        .insert_resource(heatmap_per_run)
+        .add_startup_system_to_stage(StartupStage::PreStartup, setup_circle_texture)
+        .add_startup_system_to_stage(StartupStage::PreStartup, load_font)
+        .add_startup_system_to_stage(StartupStage::PreStartup, load_scenario)
+        .add_startup_system_to_stage(StartupStage::PreStartup, clamp_border_wall)
+        .add_startup_system_to_stage(StartupStage::PreStartup, load_key_bindings)
+        .add_startup_system(set_window_icon)
+        .add_startup_system(find_session)
+        .add_startup_system(setup_camera)
+        .add_startup_system(setup_snake_game)
+        .add_startup_system(setup_ice)
+        .add_startup_system(setup_border_wall)
+        .add_startup_system(init_inputs)
+        .add_startup_system(setup_hud)
+        .add_startup_system(setup_grid_labels)
+        .add_startup_system(setup_projection)
+        .add_startup_system(setup_spawn_markers)
+        .insert_resource(SnakeSegments::default())
+        .insert_resource(LastTailPosition::default())
+        .insert_resource(RewindHistory::default())
+        .insert_resource(FoodSequence::default())
+        .insert_resource(initial_food)
+        .insert_resource(first_food_radius)
+        .insert_resource(FirstFood(true))
+        .insert_resource(food_count)
+        .insert_resource(TickCount::default())
+        .insert_resource(StartGrace(grace_ticks.0))
+        .insert_resource(grace_ticks)
+        .insert_resource(food_after_grace)
+        .insert_resource(MovementTimer::default())
+        .insert_resource(GameClock::default())
+        .insert_resource(MaxCatchupSteps::default())
+        .insert_resource(Score::default())
+        .insert_resource(TeleportCharges::default())
+        .insert_resource(HudLayout::default())
+        .insert_resource(ReverseCharges::default())
+        .insert_resource(speedrun)
+        .insert_resource(Heatmap::default())
+        .insert_resource(PendingSession::default())
+        .insert_resource(SessionFile(SESSION_PATH.to_string()))
+        .insert_resource(Scenario::default())
+        .insert_resource(ScenarioFile(flag_value("--scenario")))
+        .add_event::<GrowthEvent>()
+        .add_state(GameState::Playing)
+        .add_system_set(
+            SystemSet::on_update(GameState::Playing)
+                .with_system(snake_movement_input.before(snake_movement))
+                .with_system(food_lifetime)
+                .with_system(movement_freeze)
+                .with_system(snake_hunger.after(snake_growth))
+                .with_system(speedrun_timer.after(snake_eating))
+                .with_system(remap_start)
+                .with_system(speed_input)
+                .with_system(hud_layout)
+                .with_system(heatmap_export)
+                .with_system(toggle_grid_labels),
+        )
+        .add_system_set(SystemSet::on_enter(GameState::Paused).with_system(spawn_pause_overlay))
+        .add_system_set(SystemSet::on_exit(GameState::Paused).with_system(despawn_pause_overlay))
+        .add_system_set(SystemSet::on_enter(GameState::Remapping).with_system(spawn_remap_text))
+        .add_system_set(SystemSet::on_update(GameState::Remapping).with_system(remap_input))
+        .add_system_set(
+            SystemSet::on_exit(GameState::Remapping)
+                .with_system(despawn_remap_text)
+                .with_system(save_key_bindings),
+        )
+        .add_event::<GameOverEvent>()
+        .add_event::<FoodEvent>()
+        .add_event::<MilestoneEvent>()
+        .add_event::<TickEvent>()
+        .insert_resource(Milestones::default())
+        .insert_resource(KeyBindings::default())
+        .insert_resource(RemapProgress::default())
+        .add_system_set(
+            SystemSet::new()
+                .with_run_criteria(movement_step)
+                .with_system(snake_movement)
+                .with_system(snake_eating.after(snake_movement))
+                .with_system(snake_growth.after(snake_eating))
+                .with_system(smart_food_flee.after(snake_eating))
+                .with_system(heatmap_record.after(snake_movement)),
+        )
+        .add_system(pause_input)
+        .add_system(
+            game_over
+                .after(snake_movement)
+                .after(heatmap_record)
+                .after(session_save),
+        )
+        // Rewind and teleport move or respawn segments outside the movement tick. Running them
+        // in PreUpdate applies their commands before the tick reads the segment positions.
+        .add_system_to_stage(CoreStage::PreUpdate, game_clock)
+        .add_system_to_stage(CoreStage::PreUpdate, snake_rewind.after(InputSystem))
+        .add_system_to_stage(CoreStage::PreUpdate, save_state.after(InputSystem))
+        .add_system_to_stage(
+            CoreStage::PreUpdate,
+            load_state.after(InputSystem).after(snake_rewind),
+        )
+        .add_system_to_stage(
+            CoreStage::PreUpdate,
+            snake_teleport.after(InputSystem).before(snake_rewind),
+        )
+        .add_system_to_stage(
+            CoreStage::PreUpdate,
+            reverse_snake.after(InputSystem).before(snake_teleport),
+        )
+        .add_system(tongue_flick)
+        .add_system(floating_text)
+        .add_system(milestone_popup.after(snake_growth))
+        .add_system(score_text)
+        .add_system(teleport_text)
+        .add_system(resume_text)
+        .add_system(session_save.after(snake_movement))
+        .add_system(projection_ghost.after(snake_movement))
+        .add_system(spawn_markers)
+        .add_system(release_food.after(snake_movement))
+        .add_system(grow_arena.after(snake_growth))
+        .add_system_set_to_stage(
+            CoreStage::PostUpdate,
+            SystemSet::new()
+                // Runs after Update's commands are applied, so despawned food is already gone.
+                .with_system(food_spawner)
+                .with_system(arena_relayout)
+                .with_system(position_translation)
+                .with_system(follow_camera.after(position_translation))
+                .with_system(size_scaling)
+                .with_system(spawn_anim.after(size_scaling)),
+        )
+        .add_system_to_stage(CoreStage::Last, apply_theme)
+        .add_system_to_stage(CoreStage::Last, outline_board)
+        .add_system_to_stage(CoreStage::Last, color_by_speed.after(apply_theme))
+        .add_system_to_stage(CoreStage::Last, segment_spacing)
+        .add_system_to_stage(CoreStage::Last, frame_limiter)
+        .add_plugins(DefaultPlugins)
+        .run();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::ecs::event::Events;
+    use raw_window_handle::{RawWindowHandle, WebHandle};
+
+    fn test_windows() -> Windows {
+        let descriptor = WindowDescriptor {
+            width: WINDOW_WIDTH,
+            height: WINDOW_HEIGHT,
+            ..default()
+        };
+        let mut windows = Windows::default();
+        windows.add(Window::new(
+            WindowId::primary(),
+            &descriptor,
+            WINDOW_WIDTH as u32,
+            WINDOW_HEIGHT as u32,
+            1.0,
+            None,
+            RawWindowHandle::Web(WebHandle::empty()),
+        ));
+        windows
+    }
+
+    fn camera_world(camera_mode: CameraMode, head: Position) -> (World, Entity) {
+        let mut world = World::new();
+        world.insert_resource(test_windows());
+        world.insert_resource(camera_mode);
+        world.insert_resource(ArenaSize::default());
+        world.insert_resource(ViewMode::Fixed);
+        world.spawn().insert(head).insert(SnakeHead {
+            direction: Direction::Up,
+        });
+        let camera = world
+            .spawn()
+            .insert(Transform::default())
+            .insert(MainCamera)
+            .id();
+        (world, camera)
+    }
+
+    #[test]
+    fn follow_camera_tracks_the_head() {
+        let mut head = Position { x: 7, y: 2 };
+        let (mut world, camera) = camera_world(CameraMode::FollowHead, head);
+        let mut stage = SystemStage::single_threaded().with_system(follow_camera);
+        let window = test_windows();
+        for x in 7..10 {
+            head.x = x;
+            for mut pos in world
+                .query_filtered::<&mut Position, With<SnakeHead>>()
+                .iter_mut(&mut world)
+            {
+                *pos = head;
+            }
+            stage.run(&mut world);
+            let expected = position_to_translation(
+                &head,
+                window.get_primary().unwrap(),
+                &ArenaSize::default(),
+            );
+            let translation = world.get::<Transform>(camera).unwrap().translation;
+            assert_eq!(translation.truncate(), expected.truncate());
+        }
+    }
+
+    #[test]
+    fn arena_center_keeps_the_camera_still() {
+        let (mut world, camera) = camera_world(CameraMode::ArenaCenter, Position { x: 7, y: 2 });
+        SystemStage::single_threaded()
+            .with_system(follow_camera)
+            .run(&mut world);
+        assert_eq!(
+            world.get::<Transform>(camera).unwrap().translation,
+            Vec3::ZERO
+        );
+    }
+
+    #[test]
+    fn border_shrinks_the_playable_area() {
+        let mut world = World::new();
+        world.insert_resource(BorderWall { thickness: 9 });
+        world.insert_resource(ArenaSize::default());
+        SystemStage::single_threaded()
+            .with_system(clamp_border_wall)
+            .run(&mut world);
+        SystemStage::single_threaded()
+            .with_system(setup_border_wall)
+            .run(&mut world);
+        let border = world.resource::<BorderWall>();
+        assert_eq!(border.thickness, MAX_BORDER_THICKNESS);
+        let start = border.start();
+        let thickness = MAX_BORDER_THICKNESS;
+        let walls: Vec<Position> = world
+            .query_filtered::<&Position, With<BorderTile>>()
+            .iter(&world)
+            .copied()
+            .collect();
+        let playable = ARENA_WIDTH * ARENA_HEIGHT - walls.len() as u32;
+        assert_eq!(
+            playable,
+            (ARENA_WIDTH - 2 * thickness) * (ARENA_HEIGHT - 2 * thickness)
+        );
+        assert!(start.iter().all(|pos| !walls.contains(pos)));
+    }
+
+    #[test]
+    fn walls_never_land_on_the_snake_or_in_front_of_it() {
+        let mut world = World::new();
+        world.insert_resource(test_windows());
+        world.insert_resource(ViewMode::Fixed);
+        world.insert_resource(UiFont(Handle::default()));
+        world.insert_resource(Score::default());
+        world.insert_resource(MovementFreeze::default());
+        world.insert_resource(ArenaSize::default());
+        // Enough to fill every cell that's allowed.
+        world.insert_resource(WallsPerFood(ARENA_WIDTH * ARENA_HEIGHT));
+        world.insert_resource(Events::<GrowthEvent>::default());
+        world.insert_resource(Events::<FoodEvent>::default());
+        let snake = [
+            Position { x: 5, y: 5 },
+            Position { x: 5, y: 4 },
+            Position { x: 5, y: 3 },
+        ];
+        world
+            .spawn()
+            .insert(snake[0])
+            .insert(SnakeSegment)
+            .insert(SnakeHead {
+                direction: Direction::Up,
+            });
+        for pos in &snake[1..] {
+            world.spawn().insert(*pos).insert(SnakeSegment);
+        }
+        world.spawn().insert(snake[0]).insert(Food);
+        SystemStage::single_threaded()
+            .with_system(snake_eating)
+            .run(&mut world);
+
+        let walls: Vec<Position> = world
+            .query_filtered::<&Position, With<Wall>>()
+            .iter(&world)
+            .copied()
+            .collect();
+        assert_eq!(
+            walls.len() as u32,
+            ARENA_WIDTH * ARENA_HEIGHT - snake.len() as u32 - 1
+        );
+        assert!(snake.iter().all(|pos| !walls.contains(pos)));
+        assert!(!walls.contains(&snake[0].step(Direction::Up)));
+    }
+
+    fn remap_world(action: Direction, key: KeyCode) -> World {
+        let mut world = World::new();
+        let bindings = KeyBindings::default();
+        let index = bindings.iter().position(|(bound, _)| *bound == action);
+        world.insert_resource(RemapProgress {
+            action: index.unwrap(),
+            rejected: None,
+        });
+        world.insert_resource(bindings);
+        world.insert_resource(State::new(GameState::Remapping));
+        let mut keyboard_input = Input::<KeyCode>::default();
+        keyboard_input.press(key);
+        world.insert_resource(keyboard_input);
+        SystemStage::single_threaded()
+            .with_system(remap_input)
+            .run(&mut world);
+        world
+    }
+
+    fn bound_key(world: &World, direction: Direction) -> KeyCode {
+        let bindings = world.resource::<KeyBindings>();
+        bindings
+            .iter()
+            .find(|(bound, _)| *bound == direction)
+            .unwrap()
+            .1
+    }
+
+    #[test]
+    fn rebinding_up_to_w_steers_up_with_w() {
+        let mut world = remap_world(Direction::Up, KeyCode::W);
+        assert_eq!(bound_key(&world, Direction::Up), KeyCode::W);
+
+        world.insert_resource(BufferMode::Buffered);
+        world.insert_resource(TurboTap(false));
+        world.insert_resource(LoneHeadReverse(false));
+        world.insert_resource(TwoHeaded(false));
+        world.insert_resource(MovementTimer::default());
+        world.insert_resource(SnakeSegments::default());
+        let mut keyboard_input = Input::<KeyCode>::default();
+        keyboard_input.press(KeyCode::W);
+        world.insert_resource(keyboard_input);
+        let head = world
+            .spawn()
+            .insert(SnakeHead {
+                direction: Direction::Left,
+            })
+            .insert(InputBuffer {
+                inputs: VecDeque::new(),
+            })
+            .id();
+        SystemStage::single_threaded()
+            .with_system(snake_movement_input)
+            .run(&mut world);
+        let inputs = &world.get::<InputBuffer>(head).unwrap().inputs;
+        assert_eq!(inputs.iter().copied().collect::<Vec<_>>(), [Direction::Up]);
+    }
+
+    #[test]
+    fn hotkeys_cannot_be_bound_for_steering() {
+        let world = remap_world(Direction::Up, TELEPORT_KEY);
+        assert_eq!(bound_key(&world, Direction::Up), KeyCode::Up);
+        assert_eq!(
+            world.resource::<RemapProgress>().rejected,
+            Some(TELEPORT_KEY)
+        );
+        let mut bindings = KeyBindings::default();
+        bindings[0].1 = PAUSE_KEY;
+        assert!(bindings.validate().is_err());
+        assert!(KeyBindings::default().validate().is_ok());
+    }
+
+    #[test]
+    fn milestones_grow_the_arena_bounds() {
+        let mut world = World::new();
+        world.insert_resource(ArenaGrowth(true));
+        world.insert_resource(ArenaSize::default());
+        world.insert_resource(Events::<MilestoneEvent>::default());
+        let past_edge = Position {
+            x: ARENA_WIDTH as i32 + 1,
+            y: ARENA_HEIGHT as i32 + 1,
+        };
+        assert!(!past_edge.in_arena(world.resource::<ArenaSize>()));
+
+        world
+            .resource_mut::<Events<MilestoneEvent>>()
+            .send(MilestoneEvent(10));
+        SystemStage::single_threaded()
+            .with_system(grow_arena)
+            .run(&mut world);
+        assert!(past_edge.in_arena(world.resource::<ArenaSize>()));
+    }
+
+    #[test]
+    fn heatmap_counts_survive_a_resize() {
+        let from = ArenaSize::default();
+        let to = ArenaSize {
+            width: from.width + 2,
+            height: from.height + 2,
+        };
+        let cell = Position { x: 4, y: 7 };
+        let mut heatmap = Heatmap::new(&from);
+        heatmap[from.index(&cell)] = 3;
+        heatmap.resize(&from, &to);
+        assert_eq!(heatmap.len(), (to.width * to.height) as usize);
+        assert_eq!(heatmap[to.index(&cell)], 3);
+    }
+
+    // A bare snake plus everything the movement systems read; nothing is drawn.
+    fn snake_world(cells: &[Position], direction: Direction) -> World {
+        let mut world = World::new();
+        world.insert_resource(BufferMode::Buffered);
+        world.insert_resource(TurnSmoothing::default());
+        world.insert_resource(CoalesceInputs::default());
+        world.insert_resource(ExemptNeck::default());
+        world.insert_resource(LoneHeadReverse::default());
+        world.insert_resource(ShedOnWall::default());
+        world.insert_resource(SurvivalScorePerTick::default());
+        world.insert_resource(TurboTap::default());
+        world.insert_resource(TwoHeaded::default());
+        world.insert_resource(ArenaSize::default());
+        world.insert_resource(KeyBindings::default());
+        world.insert_resource(MovementTimer::default());
+        world.insert_resource(MovementFreeze::default());
+        world.insert_resource(LastTailPosition::default());
+        world.insert_resource(RewindHistory::default());
+        world.insert_resource(TickCount::default());
+        world.insert_resource(StartGrace::default());
+        world.insert_resource(Score::default());
+        world.insert_resource(Input::<KeyCode>::default());
+        world.insert_resource(Events::<GameOverEvent>::default());
+        world.insert_resource(Events::<FoodEvent>::default());
+        world.insert_resource(Events::<TickEvent>::default());
+        let mut segments = vec![world
+            .spawn()
+            .insert(cells[0])
+            .insert(SnakeSegment)
+            .insert(SnakeHead { direction })
+            .id()];
+        for pos in &cells[1..] {
+            segments.push(world.spawn().insert(*pos).insert(SnakeSegment).id());
+        }
+        world.insert_resource(SnakeSegments(segments));
+        world.spawn().insert(InputBuffer {
+            inputs: VecDeque::new(),
+        });
+        world
+    }
+
+    // A snake of three heading left, so Right would turn it back into its neck.
+    fn leftward_snake() -> World {
+        snake_world(
+            &[
+                Position { x: 10, y: 5 },
+                Position { x: 11, y: 5 },
+                Position { x: 12, y: 5 },
+            ],
+            Direction::Left,
+        )
+    }
+
+    // One frame with just `key` pressed.
+    fn press(world: &mut World, key: KeyCode) {
+        let mut keyboard_input = Input::<KeyCode>::default();
+        keyboard_input.press(key);
+        world.insert_resource(keyboard_input);
+        SystemStage::single_threaded()
+            .with_system(snake_movement_input)
+            .run(world);
+    }
+
+    fn step(world: &mut World) {
+        SystemStage::single_threaded()
+            .with_system(snake_movement)
+            .run(world);
+    }
+
+    fn head(world: &mut World) -> (Position, Direction) {
+        let mut heads = world.query::<(&Position, &SnakeHead)>();
+        let (pos, head) = heads.iter(world).next().unwrap();
+        (*pos, head.direction)
+    }
+
+    fn snake_cells(world: &World) -> Vec<Position> {
+        world
+            .resource::<SnakeSegments>()
+            .iter()
+            .map(|e| *world.get::<Position>(*e).unwrap())
+            .collect()
+    }
+
+    fn game_overs(world: &World) -> usize {
+        let events = world.resource::<Events<GameOverEvent>>();
+        events.get_reader().iter(events).count()
+    }
+
+    #[test]
+    fn up_then_right_on_a_leftward_snake_takes_two_ticks() {
+        let mut world = leftward_snake();
+        press(&mut world, KeyCode::Up);
+        press(&mut world, KeyCode::Right);
+        step(&mut world);
+        assert_eq!(head(&mut world), (Position { x: 10, y: 6 }, Direction::Up));
+        step(&mut world);
+        assert_eq!(
+            head(&mut world),
+            (Position { x: 11, y: 6 }, Direction::Right)
+        );
+        assert_eq!(game_overs(&world), 0);
+    }
+
+    #[test]
+    fn double_tapped_reversal_is_ignored() {
+        let mut world = leftward_snake();
+        press(&mut world, KeyCode::Right);
+        press(&mut world, KeyCode::Right);
+        step(&mut world);
+        assert_eq!(head(&mut world), (Position { x: 9, y: 5 }, Direction::Left));
+        assert_eq!(game_overs(&world), 0);
+    }
+
+    #[test]
+    fn up_down_right_never_reverses() {
+        let mut world = leftward_snake();
+        for key in [KeyCode::Up, KeyCode::Down, KeyCode::Right] {
+            press(&mut world, key);
+        }
+        // Down is dropped as a reversal of Up, so Right follows Up.
+        for _ in 0..3 {
+            step(&mut world);
+        }
+        assert_eq!(
+            head(&mut world),
+            (Position { x: 12, y: 6 }, Direction::Right)
+        );
+        assert_eq!(game_overs(&world), 0);
+    }
+
+    #[test]
+    fn immediate_turns_are_checked_against_the_neck() {
+        let mut world = leftward_snake();
+        world.insert_resource(BufferMode::Immediate);
+        press(&mut world, KeyCode::Up);
+        press(&mut world, KeyCode::Right);
+        step(&mut world);
+        assert_eq!(head(&mut world), (Position { x: 10, y: 6 }, Direction::Up));
+        assert_eq!(game_overs(&world), 0);
+    }
+
+    #[test]
+    fn coalesced_inputs_skip_an_illegal_last_turn() {
+        let mut world = leftward_snake();
+        world.insert_resource(CoalesceInputs(true));
+        press(&mut world, KeyCode::Up);
+        press(&mut world, KeyCode::Right);
+        step(&mut world);
+        let (_, direction) = head(&mut world);
+        assert_ne!(direction, Direction::Right);
+        assert_eq!(game_overs(&world), 0);
+        assert!(snake_cells(&world)
+            .windows(2)
+            .all(|pair| pair[0] != pair[1]));
+    }
+
+    // Picks the offered cell at each scripted index in turn and remembers what it was offered.
+    struct ScriptedRandom {
+        picks: VecDeque<usize>,
+        offered: Vec<Vec<Position>>,
+    }
+
+    impl ScriptedRandom {
+        fn new(picks: &[usize]) -> Self {
+            Self {
+                picks: picks.iter().copied().collect(),
+                offered: Vec::new(),
+            }
+        }
+    }
+
+    impl RandomSource for ScriptedRandom {
+        fn next_free_cell(&mut self, free: &[Position]) -> Position {
+            self.offered.push(free.to_vec());
+            free[self.picks.pop_front().unwrap_or(0)]
+        }
+
+        fn roll(&mut self) -> f32 {
+            0.0
+        }
+    }
+
+    // Every cell of the arena except `free`.
+    fn all_but(free: &[Position]) -> Vec<Position> {
+        ArenaSize::default()
+            .cells()
+            .filter(|cell| !free.contains(cell))
+            .collect()
+    }
+
+    #[test]
+    fn scripted_food_falls_back_to_the_random_source() {
+        let arena = ArenaSize::default();
+        let taken = Position { x: 1, y: 1 };
+        let scripted = Position { x: 2, y: 2 };
+        let mut sequence =
+            FoodSequence(VecDeque::from([Position { x: -1, y: 0 }, taken, scripted]));
+        let mut random = ScriptedRandom::new(&[3]);
+
+        let first = next_food_position(&arena, &mut sequence, &mut random, &[taken], None);
+        assert_eq!(first, scripted);
+        assert!(random.offered.is_empty());
+
+        let second = next_food_position(&arena, &mut sequence, &mut random, &[taken], None);
+        let free = free_cells(&arena, &[taken]);
+        assert_eq!(random.offered.len(), 1);
+        assert_eq!(random.offered[0], free);
+        assert_eq!(second, free[3]);
+    }
+
+    #[test]
+    fn avoid_ahead_needs_enough_cells_off_the_cone() {
+        let arena = ArenaSize::default();
+        let heading = Some((Position { x: 0, y: 0 }, Direction::Right));
+        let ahead = [
+            Position { x: 1, y: 0 },
+            Position { x: 2, y: 1 },
+            Position { x: 3, y: 3 },
+        ];
+        let aside = (1..=FOOD_BIAS_MIN_CELLS as i32)
+            .map(|y| Position { x: 0, y })
+            .collect::<Vec<_>>();
+
+        // One cell short of the minimum off the cone, so any free cell will do.
+        let short = [&ahead[..], &aside[1..]].concat();
+        let mut random = ScriptedRandom::new(&[]);
+        let mut sequence = FoodSequence::default();
+        next_food_position(
+            &arena,
+            &mut sequence,
+            &mut random,
+            &all_but(&short),
+            heading,
+        );
+        assert_eq!(random.offered[0].len(), short.len());
+
+        let enough = [&ahead[..], &aside[..]].concat();
+        let mut random = ScriptedRandom::new(&[]);
+        next_food_position(
+            &arena,
+            &mut sequence,
+            &mut random,
+            &all_but(&enough),
+            heading,
+        );
+        assert_eq!(random.offered[0], aside);
+    }
+
+    // A frame that took at least `delta`; the actual delta is in `Time::delta`.
+    fn frame_time(delta: Duration) -> Time {
+        let mut time = Time::default();
+        time.update();
+        std::thread::sleep(delta);
+        time.update();
+        time
+    }
+
+    fn timed_snake(time: Time, step_secs: f32) -> World {
+        let mut world = leftward_snake();
+        world.insert_resource(time);
+        world.insert_resource(GameClock::default());
+        world.insert_resource(MovementTimer {
+            timer: Timer::from_seconds(step_secs, true),
+            ..default()
+        });
+        world.insert_resource(GameSpeed::default());
+        world.insert_resource(MaxCatchupSteps::default());
+        world.insert_resource(State::new(GameState::Playing));
+        world
+    }
+
+    // One frame of the movement set, run criteria included. Run criteria are checked before a
+    // stage's systems, so the clock gets a stage of its own, as in PreUpdate.
+    fn movement_frame(world: &mut World) {
+        SystemStage::single_threaded()
+            .with_system(game_clock)
+            .run(world);
+        SystemStage::single_threaded()
+            .with_system_set(
+                SystemSet::new()
+                    .with_run_criteria(movement_step)
+                    .with_system(snake_movement),
+            )
+            .run(world);
+    }
+
+    #[test]
+    fn a_long_frame_runs_at_most_max_catchup_steps() {
+        let mut world = timed_snake(frame_time(Duration::from_millis(20)), 0.001);
+        movement_frame(&mut world);
+        let max = **world.resource::<MaxCatchupSteps>();
+        assert_eq!(**world.resource::<TickCount>(), max as u64);
+        assert_eq!(
+            head(&mut world).0,
+            Position {
+                x: 10 - max as i32,
+                y: 5
+            }
+        );
+
+        // The time past the cap is dropped, not run on the next frame.
+        world.insert_resource(Time::default());
+        movement_frame(&mut world);
+        assert_eq!(**world.resource::<TickCount>(), max as u64);
+    }
+
+    #[test]
+    fn nothing_moves_while_paused() {
+        let mut world = timed_snake(frame_time(Duration::from_millis(20)), 0.001);
+        world.insert_resource(State::new(GameState::Paused));
+        movement_frame(&mut world);
+        assert_eq!(**world.resource::<TickCount>(), 0);
+        assert_eq!(head(&mut world).0, Position { x: 10, y: 5 });
+        // The paused frame's time isn't banked for later either.
+        assert_eq!(
+            world.resource::<MovementTimer>().timer.elapsed(),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn n_steps_advance_the_tick_count_by_n() {
+        let mut world = timed_snake(Time::default(), MOVEMENT_STEP_SECS);
+        world.insert_resource(MaxCatchupSteps(10));
+        for frame in 1..=4 {
+            world.resource_mut::<MovementTimer>().pending_steps = 2;
+            movement_frame(&mut world);
+            assert_eq!(**world.resource::<TickCount>(), frame * 2);
+        }
+        let events = world.resource::<Events<TickEvent>>();
+        let ticks = events
+            .get_reader()
+            .iter(events)
+            .map(|TickEvent(tick)| *tick)
+            .collect::<Vec<_>>();
+        assert_eq!(ticks, (1..=8).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn double_speed_runs_twice_the_steps() {
+        let time = frame_time(Duration::from_millis(30));
+        // Leaves the one-speed frame between two and three steps, well clear of either edge.
+        let step_secs = time.delta().as_secs_f32() / 2.2;
+        let steps = |speed: f32| {
+            let mut world = timed_snake(time.clone(), step_secs);
+            world.insert_resource(GameSpeed(speed));
+            world.insert_resource(MaxCatchupSteps(10));
+            movement_frame(&mut world);
+            let ticks = **world.resource::<TickCount>();
+            ticks
+        };
+        assert_eq!(steps(1.0), 2);
+        assert_eq!(steps(2.0), 4);
+    }
+
+    #[test]
+    fn movement_starts_right_after_the_grace_ticks() {
+        let mut world = timed_snake(Time::default(), MOVEMENT_STEP_SECS);
+        world.insert_resource(StartGrace(2));
+        press(&mut world, KeyCode::Up);
+        for _ in 0..2 {
+            world.resource_mut::<MovementTimer>().pending_steps = 1;
+            movement_frame(&mut world);
+            assert_eq!(head(&mut world).0, Position { x: 10, y: 5 });
+        }
+        // The turn pressed during the grace period is the first one taken.
+        world.resource_mut::<MovementTimer>().pending_steps = 1;
+        movement_frame(&mut world);
+        assert_eq!(head(&mut world), (Position { x: 10, y: 6 }, Direction::Up));
+        assert_eq!(**world.resource::<TickCount>(), 1);
+    }
+
+    // The resources spawn_segment and spawn_head read.
+    fn insert_look(world: &mut World) {
+        world.insert_resource(SpriteStyle::default());
+        world.insert_resource(CircleTexture(Handle::default()));
+        world.insert_resource(ShowTongue(false));
+        world.insert_resource(Outline::default());
+        world.insert_resource(TwoHeaded::default());
+    }
+
+    // Steps the leftward snake once, eats on that tick and grows from `anchor`.
+    fn grown_snake(anchor: GrowthAnchor) -> (World, Vec<Entity>) {
+        let mut world = leftward_snake();
+        insert_look(&mut world);
+        world.insert_resource(anchor);
+        world.insert_resource(Milestones::default());
+        world.insert_resource(Events::<GrowthEvent>::default());
+        world.insert_resource(Events::<MilestoneEvent>::default());
+        let before = world.resource::<SnakeSegments>().0.clone();
+        step(&mut world);
+        world
+            .resource_mut::<Events<GrowthEvent>>()
+            .send(GrowthEvent);
+        SystemStage::single_threaded()
+            .with_system(snake_growth)
+            .run(&mut world);
+        (world, before)
+    }
+
+    #[test]
+    fn tail_growth_appends_on_the_vacated_tail_cell() {
+        let (world, before) = grown_snake(GrowthAnchor::Tail);
+        let segments = world.resource::<SnakeSegments>();
+        assert_eq!(segments[..3], before[..]);
+        assert_eq!(
+            snake_cells(&world),
+            [
+                Position { x: 9, y: 5 },
+                Position { x: 10, y: 5 },
+                Position { x: 11, y: 5 },
+                Position { x: 12, y: 5 },
+            ]
+        );
+    }
+
+    #[test]
+    fn head_growth_inserts_behind_the_head() {
+        let (world, before) = grown_snake(GrowthAnchor::Head);
+        let segments = world.resource::<SnakeSegments>();
+        assert_eq!(segments[0], before[0]);
+        assert!(!before.contains(&segments[1]));
+        assert_eq!(segments[2..], before[1..]);
+        // Same cells as tail growth, but the older body segments are back where they started.
+        assert_eq!(
+            snake_cells(&world),
+            [
+                Position { x: 9, y: 5 },
+                Position { x: 10, y: 5 },
+                Position { x: 11, y: 5 },
+                Position { x: 12, y: 5 },
+            ]
+        );
+    }
+
+    fn reverse(world: &mut World) {
+        let mut keyboard_input = Input::<KeyCode>::default();
+        keyboard_input.press(REVERSE_KEY);
+        world.insert_resource(keyboard_input);
+        SystemStage::single_threaded()
+            .with_system(reverse_snake)
+            .run(world);
+    }
+
+    // Heading up with the tail bent off to the right, so turning back the way it came would be
+    // wrong for the reversed snake.
+    fn bent_snake(cells: &[Position]) -> World {
+        let mut world = snake_world(cells, Direction::Up);
+        world.insert_resource(State::new(GameState::Playing));
+        world.insert_resource(Theme::default());
+        world.insert_resource(ReverseCharges(2));
+        world
+    }
+
+    fn head_entity(world: &mut World) -> Entity {
+        let mut heads = world.query_filtered::<Entity, With<SnakeHead>>();
+        let mut heads = heads.iter(world);
+        let head = heads.next().unwrap();
+        assert!(heads.next().is_none());
+        head
+    }
+
+    #[test]
+    fn reversing_twice_restores_the_snake() {
+        let cells = [
+            Position { x: 10, y: 6 },
+            Position { x: 10, y: 5 },
+            Position { x: 11, y: 5 },
+        ];
+        let mut world = bent_snake(&cells);
+        let segments = world.resource::<SnakeSegments>().0.clone();
+
+        reverse(&mut world);
+        assert_eq!(head_entity(&mut world), segments[2]);
+        assert_eq!(head(&mut world).1, Direction::Right);
+
+        reverse(&mut world);
+        assert_eq!(world.resource::<SnakeSegments>().0, segments);
+        assert_eq!(head_entity(&mut world), segments[0]);
+        assert_eq!(head(&mut world), (cells[0], Direction::Up));
+        assert_eq!(snake_cells(&world), cells);
+    }
+
+    #[test]
+    fn reversing_after_eating_heads_away_from_the_neck() {
+        // Two segments just grown onto the tail cell.
+        let mut world = bent_snake(&[
+            Position { x: 10, y: 6 },
+            Position { x: 10, y: 5 },
+            Position { x: 11, y: 5 },
+            Position { x: 11, y: 5 },
+        ]);
+        reverse(&mut world);
+        assert_eq!(
+            head(&mut world),
+            (Position { x: 11, y: 5 }, Direction::Right)
+        );
+    }
+
+    #[test]
+    fn a_shed_keeps_the_front_half_and_turns_off_the_wall() {
+        let cells = (0..5).map(|x| Position { x, y: 5 }).collect::<Vec<_>>();
+        let mut world = snake_world(&cells, Direction::Left);
+        world.insert_resource(ShedOnWall(true));
+        step(&mut world);
+        assert_eq!(snake_cells(&world), cells[..3]);
+        let (pos, direction) = head(&mut world);
+        assert_eq!(pos, cells[0]);
+        assert!(matches!(direction, Direction::Up | Direction::Down));
+        assert_eq!(world.resource::<LastTailPosition>().0, Some(cells[2]));
+        let events = world.resource::<Events<TickEvent>>();
+        assert_eq!(events.get_reader().iter(events).count(), 1);
+
+        // Heading along the wall now, so the next tick doesn't shed again.
+        step(&mut world);
+        assert_eq!(snake_cells(&world).len(), 3);
+        assert_eq!(head(&mut world).0, cells[0].step(direction));
+        assert_eq!(game_overs(&world), 0);
+    }
+
+    // A six-long snake that goes hungry 1.6 frames' worth of time after eating.
+    fn hungry_snake(time: Time) -> (World, SystemStage) {
+        let cells = (10..16).map(|x| Position { x, y: 5 }).collect::<Vec<_>>();
+        let mut world = snake_world(&cells, Direction::Left);
+        let interval = time.delta().as_secs_f32() * 1.6;
+        world.insert_resource(Hunger(Some(Timer::from_seconds(interval, true))));
+        world.insert_resource(time);
+        world.insert_resource(GameSpeed::default());
+        world.insert_resource(Events::<GrowthEvent>::default());
+        // One stage throughout, so its event reader remembers what it has already seen.
+        let stage = SystemStage::single_threaded().with_system(snake_hunger);
+        (world, stage)
+    }
+
+    #[test]
+    fn hunger_trims_a_segment_per_interval() {
+        let (mut world, mut stage) = hungry_snake(frame_time(Duration::from_millis(10)));
+        let mut lengths = Vec::new();
+        for _ in 0..5 {
+            stage.run(&mut world);
+            lengths.push(world.resource::<SnakeSegments>().len());
+        }
+        assert_eq!(lengths, [6, 5, 5, 4, 3]);
+    }
+
+    #[test]
+    fn eating_resets_hunger() {
+        let (mut world, mut stage) = hungry_snake(frame_time(Duration::from_millis(10)));
+        stage.run(&mut world);
+        world
+            .resource_mut::<Events<GrowthEvent>>()
+            .send(GrowthEvent);
+        let mut lengths = Vec::new();
+        for _ in 0..3 {
+            stage.run(&mut world);
+            lengths.push(world.resource::<SnakeSegments>().len());
+        }
+        // Without the meal the second frame would already have cost a segment.
+        assert_eq!(lengths, [6, 6, 5]);
+    }
+
+    // Taps Up, Left, Up on the leftward snake and lets three ticks go by.
+    fn whip(turn_smoothing: bool) -> Position {
+        let mut world = leftward_snake();
+        world.insert_resource(TurnSmoothing(turn_smoothing));
+        for key in [KeyCode::Up, KeyCode::Left, KeyCode::Up] {
+            press(&mut world, key);
+        }
+        assert_eq!(
+            world
+                .query::<&InputBuffer>()
+                .iter(&world)
+                .next()
+                .unwrap()
+                .inputs
+                .len(),
+            3
+        );
+        for _ in 0..3 {
+            step(&mut world);
+        }
+        head(&mut world).0
+    }
+
+    #[test]
+    fn turn_smoothing_collapses_back_and_forth_turns() {
+        // Smoothed, only the net turn is left: three ticks straight up.
+        assert_eq!(whip(true), Position { x: 10, y: 8 });
+        // Otherwise every tap is its own turn.
+        assert_eq!(whip(false), Position { x: 9, y: 7 });
+    }
+
+    #[test]
+    fn scrolling_view_keeps_the_head_at_the_center() {
+        let (mut world, _) = camera_world(CameraMode::ArenaCenter, Position { x: 3, y: 14 });
+        world.insert_resource(ViewMode::Scrolling);
+        let food = world
+            .spawn()
+            .insert(Position { x: 4, y: 14 })
+            .insert(Transform::default())
+            .id();
+        let head = head_entity(&mut world);
+        world.entity_mut(head).insert(Transform::default());
+        SystemStage::single_threaded()
+            .with_system(position_translation)
+            .run(&mut world);
+        assert_eq!(
+            world.get::<Transform>(head).unwrap().translation,
+            Vec3::ZERO
+        );
+        // Everything else moves with the arena, one tile right of the head here.
+        let tile = WINDOW_WIDTH / ARENA_WIDTH as f32;
+        let translation = world.get::<Transform>(food).unwrap().translation;
+        assert!((translation.x - tile).abs() < 1e-3);
+        assert_eq!(translation.y, 0.0);
+    }
+
+    #[test]
+    fn immediate_mode_turns_on_the_keypress_frame() {
+        let mut world = leftward_snake();
+        world.insert_resource(BufferMode::Immediate);
+        press(&mut world, KeyCode::Up);
+        // No movement tick yet: the head has turned but not moved.
+        assert_eq!(head(&mut world), (Position { x: 10, y: 5 }, Direction::Up));
+        assert!(world
+            .query::<&InputBuffer>()
+            .iter(&world)
+            .all(|buffer| buffer.inputs.is_empty()));
+        step(&mut world);
+        assert_eq!(head(&mut world), (Position { x: 10, y: 6 }, Direction::Up));
+    }
+
+    fn teleport(world: &mut World) {
+        let mut keyboard_input = Input::<KeyCode>::default();
+        keyboard_input.press(TELEPORT_KEY);
+        world.insert_resource(keyboard_input);
+        SystemStage::single_threaded()
+            .with_system(snake_teleport)
+            .run(world);
+    }
+
+    #[test]
+    fn teleport_keeps_the_shape_clear_of_walls() {
+        let cells = [
+            Position { x: 1, y: 2 },
+            Position { x: 1, y: 1 },
+            Position { x: 2, y: 1 },
+        ];
+        let mut world = bent_snake(&cells);
+        world.insert_resource(ArenaSize {
+            width: 6,
+            height: 6,
+        });
+        world.insert_resource(TeleportCharges(20));
+        let walls = [
+            Position { x: 4, y: 4 },
+            Position { x: 3, y: 0 },
+            Position { x: 0, y: 4 },
+        ];
+        for pos in walls {
+            world.spawn().insert(pos).insert(Wall);
+        }
+        let shape = |cells: &[Position]| {
+            cells
+                .iter()
+                .map(|pos| (pos.x - cells[0].x, pos.y - cells[0].y))
+                .collect::<Vec<_>>()
+        };
+        for used in 1..=20 {
+            let before = snake_cells(&world);
+            teleport(&mut world);
+            let after = snake_cells(&world);
+            assert_ne!(after[0], before[0]);
+            assert_eq!(shape(&after), shape(&cells));
+            let arena = world.resource::<ArenaSize>();
+            assert!(after.iter().all(|pos| pos.in_arena(arena)));
+            assert!(after.iter().all(|pos| !walls.contains(pos)));
+            let front = after[0].step(Direction::Up);
+            assert!(front.in_arena(arena) && !walls.contains(&front));
+            assert_eq!(**world.resource::<TeleportCharges>(), 20 - used);
+        }
+    }
+
+    #[test]
+    fn teleport_with_nowhere_to_go_keeps_its_charge() {
+        let cells = [Position { x: 1, y: 1 }, Position { x: 1, y: 0 }];
+        let mut world = bent_snake(&cells);
+        world.insert_resource(ArenaSize {
+            width: 3,
+            height: 3,
+        });
+        world.insert_resource(TeleportCharges(2));
+        // The only other spot that fits the snake faces the top edge.
+        for pos in [0, 2]
+            .into_iter()
+            .flat_map(|x| (0..3).map(move |y| Position { x, y }))
+        {
+            world.spawn().insert(pos).insert(Wall);
+        }
+        teleport(&mut world);
+        assert_eq!(snake_cells(&world), cells);
+        assert_eq!(**world.resource::<TeleportCharges>(), 2);
+    }
+
+    // The leftward snake plus what food_spawner and a restart read, with `count` food per round.
+    fn food_world(count: usize) -> World {
+        let mut world = leftward_snake();
+        insert_look(&mut world);
+        world.insert_resource(FoodCount(count));
+        world.insert_resource(FoodTimeout(None));
+        world.insert_resource(FoodBias::default());
+        world.insert_resource(ReachableFood::default());
+        world.insert_resource(FreezeFoodChance::default());
+        world.insert_resource(SmartFoodConfig::default());
+        world.insert_resource(FoodAfterGrace::default());
+        world.insert_resource(FirstFoodRadius::default());
+        world.insert_resource(InitialFood::default());
+        world.insert_resource(FirstFood(true));
+        world.insert_resource(FoodSequence::default());
+        world.insert_resource(FoodRandom(Box::new(SeededRandom::new(7))));
+        world.insert_resource(RecentFoodSpawns::default());
+        world.insert_resource(Scenario::default());
+        world.insert_resource(BorderWall::default());
+        world.insert_resource(TeleportCharges::default());
+        world.insert_resource(ReverseCharges::default());
+        world.insert_resource(Speedrun::default());
+        world.insert_resource(GraceTicks(0));
+        world.insert_resource(HeatmapPerRun(false));
+        world.insert_resource(Heatmap::default());
+        world.insert_resource(Hunger::default());
+        world.insert_resource(PendingSession::default());
+        // Never the real session.tmp, which game_over deletes.
+        let session = std::env::temp_dir().join("snake-test-session.tmp");
+        world.insert_resource(SessionFile(session.to_string_lossy().into_owned()));
+        world
+    }
+
+    // food_spawner runs in PostUpdate, after the frame's other commands are applied.
+    fn spawn_food_frame(world: &mut World) {
+        SystemStage::single_threaded()
+            .with_system(food_spawner)
+            .run(world);
+    }
+
+    fn food_cells(world: &mut World) -> Vec<Position> {
+        world
+            .query_filtered::<&Position, With<Food>>()
+            .iter(world)
+            .copied()
+            .collect()
+    }
+
+    #[test]
+    fn restarting_leaves_exactly_food_count_food() {
+        let mut world = food_world(3);
+        for _ in 0..3 {
+            world.resource_mut::<Events<FoodEvent>>().send(FoodEvent);
+        }
+        spawn_food_frame(&mut world);
+        assert_eq!(food_cells(&mut world).len(), 3);
+
+        // Dying on the tick the snake eats queues a replacement on top of the restart's own.
+        world.resource_mut::<Events<FoodEvent>>().send(FoodEvent);
+        world
+            .resource_mut::<Events<GameOverEvent>>()
+            .send(GameOverEvent);
+        let mut update = SystemStage::single_threaded().with_system(game_over);
+        update.run(&mut world);
+        spawn_food_frame(&mut world);
+        assert_eq!(food_cells(&mut world).len(), 3);
+
+        world.resource_mut::<Events<FoodEvent>>().send(FoodEvent);
+        spawn_food_frame(&mut world);
+        assert_eq!(food_cells(&mut world).len(), 3);
+    }
+
+    #[test]
+    fn the_first_frame_has_food_count_food() {
+        let mut world = food_world(4);
+        // setup_snake_game lays out its own snake.
+        for ent in world.resource::<SnakeSegments>().0.clone() {
+            world.despawn(ent);
+        }
+        SystemStage::single_threaded()
+            .with_system(setup_snake_game)
+            .run(&mut world);
+        spawn_food_frame(&mut world);
+        let food = food_cells(&mut world);
+        assert_eq!(food.len(), 4);
+        let snake = snake_cells(&world);
+        assert!(food.iter().all(|pos| !snake.contains(pos)));
+    }
+
+    fn milestones_reached(world: &World) -> Vec<u32> {
+        let events = world.resource::<Events<MilestoneEvent>>();
+        events
+            .get_reader()
+            .iter(events)
+            .map(|MilestoneEvent(length)| *length)
+            .collect()
+    }
+
+    #[test]
+    fn growing_across_a_milestone_sends_it_once() {
+        let (mut world, _) = grown_snake(GrowthAnchor::Tail);
+        assert!(milestones_reached(&world).is_empty());
+        world.insert_resource(Milestones(vec![5, 6, 9]));
+        world.resource_mut::<Events<GrowthEvent>>().clear();
+        // Two foods in one tick take the snake from four to six, past both 5 and 6.
+        let mut stage = SystemStage::single_threaded().with_system(snake_growth);
+        step(&mut world);
+        for _ in 0..2 {
+            world
+                .resource_mut::<Events<GrowthEvent>>()
+                .send(GrowthEvent);
+        }
+        stage.run(&mut world);
+        assert_eq!(milestones_reached(&world), [5, 6]);
+
+        // Staying at a milestone length, or growing short of the next one, sends nothing more.
+        step(&mut world);
+        stage.run(&mut world);
+        step(&mut world);
+        world
+            .resource_mut::<Events<GrowthEvent>>()
+            .send(GrowthEvent);
+        stage.run(&mut world);
+        assert_eq!(world.resource::<SnakeSegments>().len(), 7);
+        assert_eq!(milestones_reached(&world), [5, 6]);
+    }
+
+    #[test]
+    fn two_foods_in_one_tick_grow_two_segments() {
+        let mut world = leftward_snake();
+        insert_look(&mut world);
+        world.insert_resource(test_windows());
+        world.insert_resource(ViewMode::Fixed);
+        world.insert_resource(UiFont(Handle::default()));
+        world.insert_resource(WallsPerFood(0));
+        world.insert_resource(GrowthAnchor::Tail);
+        world.insert_resource(Milestones::default());
+        world.insert_resource(Events::<GrowthEvent>::default());
+        world.insert_resource(Events::<MilestoneEvent>::default());
+        step(&mut world);
+        // Stacked food can only come from an old save, but it shouldn't lose a meal either.
+        for _ in 0..2 {
+            world.spawn().insert(Position { x: 9, y: 5 }).insert(Food);
+        }
+        SystemStage::single_threaded()
+            .with_system(snake_eating)
+            .with_system(snake_growth.after(snake_eating))
+            .run(&mut world);
+        assert!(food_cells(&mut world).is_empty());
+        assert_eq!(**world.resource::<Score>(), 2 * FOOD_SCORE);
+        let events = world.resource::<Events<FoodEvent>>();
+        assert_eq!(events.get_reader().iter(events).count(), 2);
+        let tail = Position { x: 12, y: 5 };
+        assert_eq!(snake_cells(&world)[3..], [tail, tail]);
+
+        // The stacked segments unfold one per tick behind the tail.
+        step(&mut world);
+        step(&mut world);
+        let cells = snake_cells(&world);
+        assert_eq!(
+            cells,
+            (7..=11).map(|x| Position { x, y: 5 }).collect::<Vec<_>>()
+        );
+        assert_eq!(game_overs(&world), 0);
+    }
+
+    #[test]
+    fn turns_are_dropped_only_on_ice() {
+        let mut world = leftward_snake();
+        world
+            .spawn()
+            .insert(Position { x: 10, y: 5 })
+            .insert(IceTile);
+        press(&mut world, KeyCode::Up);
+        step(&mut world);
+        assert_eq!(head(&mut world), (Position { x: 9, y: 5 }, Direction::Left));
+        // Off the ice the same turn works, and the one pressed on the ice isn't replayed.
+        step(&mut world);
+        assert_eq!(head(&mut world), (Position { x: 8, y: 5 }, Direction::Left));
+        press(&mut world, KeyCode::Up);
+        step(&mut world);
+        assert_eq!(head(&mut world), (Position { x: 8, y: 6 }, Direction::Up));
+    }
+
+    #[test]
+    fn avoid_ahead_keeps_food_out_of_the_cone() {
+        let arena = ArenaSize::default();
+        let head = Position {
+            x: arena.width as i32 / 2,
+            y: arena.height as i32 / 2,
+        };
+        let occupied = [head, head.step(Direction::Left)];
+        let ahead_share = |bias: bool| {
+            let mut random = SeededRandom::new(11);
+            let mut sequence = FoodSequence::default();
+            let spawns = 2000;
+            let ahead = (0..spawns)
+                .map(|_| {
+                    next_food_position(
+                        &arena,
+                        &mut sequence,
+                        &mut random,
+                        &occupied,
+                        bias.then_some((head, Direction::Right)),
+                    )
+                })
+                .filter(|pos| head.is_ahead(Direction::Right, *pos))
+                .count();
+            ahead as f32 / spawns as f32
+        };
+        assert_eq!(ahead_share(true), 0.0);
+        // Unbiased, the cone gets its share of the board's cells.
+        let cone = arena
+            .cells()
+            .filter(|cell| head.is_ahead(Direction::Right, *cell))
+            .count() as f32
+            / (arena.cells().count() - occupied.len()) as f32;
+        assert!((ahead_share(false) - cone).abs() < 0.05);
+    }
+
+    #[test]
+    fn popups_stand_still_while_paused() {
+        let mut world = World::new();
+        world.insert_resource(frame_time(Duration::from_millis(10)));
+        world.insert_resource(GameSpeed::default());
+        world.insert_resource(GameClock::default());
+        world.insert_resource(ReducedMotion(false));
+        world.insert_resource(State::new(GameState::Paused));
+        let popup = world
+            .spawn()
+            .insert(FloatingText {
+                timer: Timer::from_seconds(1.0, false),
+                velocity: Vec3::Y,
+            })
+            .insert(Transform::default())
+            .insert(Text::default())
+            .id();
+        let mut stage = SystemStage::single_threaded()
+            .with_system(game_clock)
+            .with_system(floating_text.after(game_clock));
+        for _ in 0..3 {
+            stage.run(&mut world);
+        }
+        let floating = world.get::<FloatingText>(popup).unwrap();
+        assert_eq!(floating.timer.elapsed(), Duration::ZERO);
+        assert_eq!(
+            world.get::<Transform>(popup).unwrap().translation,
+            Vec3::ZERO
+        );
+
+        world.insert_resource(State::new(GameState::Playing));
+        stage.run(&mut world);
+        let delta = world.resource::<Time>().delta();
+        assert_eq!(
+            world.get::<FloatingText>(popup).unwrap().timer.elapsed(),
+            delta
+        );
+    }
+
+    #[test]
+    fn turbo_taps_step_through_collisions() {
+        let mut world = timed_snake(Time::default(), MOVEMENT_STEP_SECS);
+        world.insert_resource(TurboTap(true));
+        world.insert_resource(MaxCatchupSteps(10));
+        press(&mut world, KeyCode::Left);
+        assert_eq!(world.resource::<MovementTimer>().pending_steps, 1);
+        movement_frame(&mut world);
+        assert_eq!(head(&mut world).0, Position { x: 9, y: 5 });
+
+        // Two taps from next to the edge: the first reaches it, the second runs into it.
+        for (ent, x) in world
+            .resource::<SnakeSegments>()
+            .0
+            .clone()
+            .into_iter()
+            .zip(1..)
+        {
+            *world.get_mut::<Position>(ent).unwrap() = Position { x, y: 5 };
+        }
+        press(&mut world, KeyCode::Left);
+        press(&mut world, KeyCode::Left);
+        movement_frame(&mut world);
+        assert_eq!(**world.resource::<TickCount>(), 3);
+        assert_eq!(game_overs(&world), 1);
+    }
+
+    #[test]
+    fn the_head_is_drawn_over_food_on_the_same_cell() {
+        let (mut world, _) = camera_world(CameraMode::ArenaCenter, Position { x: 3, y: 3 });
+        let head = head_entity(&mut world);
+        world
+            .entity_mut(head)
+            .insert(ZLayer::Head)
+            .insert(Transform::default());
+        let food = world
+            .spawn()
+            .insert(Position { x: 3, y: 3 })
+            .insert(ZLayer::Food)
+            .insert(Transform::default())
+            .id();
+        SystemStage::single_threaded()
+            .with_system(position_translation)
+            .run(&mut world);
+        let z = |ent| world.get::<Transform>(ent).unwrap().translation.z;
+        assert!(z(head) > z(food));
+    }
+
+    #[test]
+    fn fleeing_food_never_stacks_on_a_tiny_board() {
+        let mut world = food_world(6);
+        world.insert_resource(ArenaSize {
+            width: 4,
+            height: 3,
+        });
+        let segments = world.resource::<SnakeSegments>().0.clone();
+        for (ent, x) in segments.iter().zip(0..) {
+            *world.get_mut::<Position>(*ent).unwrap() = Position { x, y: 0 };
+        }
+        for x in [2, 3] {
+            world
+                .spawn()
+                .insert(Position { x, y: 2 })
+                .insert(Food)
+                .insert(SmartFood { moves_left: 10 });
+        }
+        for _ in 0..6 {
+            world.resource_mut::<Events<FoodEvent>>().send(FoodEvent);
+        }
+        spawn_food_frame(&mut world);
+        let mut flee = SystemStage::single_threaded().with_system(smart_food_flee);
+        for _ in 0..6 {
+            // Chase: put the head on the free cell closest to the fleeing food.
+            let smart = world
+                .query_filtered::<&Position, With<SmartFood>>()
+                .iter(&world)
+                .copied()
+                .collect::<Vec<_>>();
+            let taken = [food_cells(&mut world), snake_cells(&world)].concat();
+            let arena = world.resource::<ArenaSize>();
+            let chase = free_cells(arena, &taken)
+                .into_iter()
+                .min_by_key(|cell| {
+                    smart
+                        .iter()
+                        .map(|pos| (pos.x - cell.x).abs() + (pos.y - cell.y).abs())
+                        .min()
+                })
+                .unwrap();
+            *world.get_mut::<Position>(segments[0]).unwrap() = chase;
+            flee.run(&mut world);
+            let mut food = food_cells(&mut world);
+            let snake = snake_cells(&world);
+            assert_eq!(food.len(), 6);
+            assert!(food.iter().all(|pos| !snake.contains(pos)));
+            food.sort_by_key(|pos| (pos.x, pos.y));
+            food.dedup();
+            assert_eq!(food.len(), 6);
+        }
+        // The chase did make the food run rather than sit boxed in.
+        assert!(world
+            .query::<&SmartFood>()
+            .iter(&world)
+            .any(|smart| smart.moves_left < 10));
+    }
+
+    #[test]
+    fn exempt_neck_turbo_turns_stay_alive() {
+        let mut world = timed_snake(Time::default(), MOVEMENT_STEP_SECS);
+        world.insert_resource(TurboTap(true));
+        world.insert_resource(ExemptNeck(true));
+        world.insert_resource(MaxCatchupSteps(10));
+        // Tight turns with extra steps in between, all in a single frame's worth of steps.
+        for key in [
+            KeyCode::Left,
+            KeyCode::Up,
+            KeyCode::Right,
+            KeyCode::Right,
+            KeyCode::Down,
+        ] {
+            press(&mut world, key);
+            world.resource_mut::<MovementTimer>().pending_steps += 1;
+            movement_frame(&mut world);
+        }
+        assert_eq!(**world.resource::<TickCount>(), 7);
+        assert_eq!(game_overs(&world), 0);
+    }
+
+    #[test]
+    fn exempt_neck_still_dies_on_the_body() {
+        let mut world = snake_world(
+            &[
+                Position { x: 10, y: 5 },
+                Position { x: 10, y: 4 },
+                Position { x: 11, y: 4 },
+                Position { x: 11, y: 5 },
+                Position { x: 12, y: 5 },
+            ],
+            Direction::Up,
+        );
+        world.insert_resource(ExemptNeck(true));
+        press(&mut world, KeyCode::Right);
+        step(&mut world);
+        assert_eq!(game_overs(&world), 1);
+    }
+
+    #[test]
+    fn survival_score_adds_one_per_tick() {
+        let mut world = leftward_snake();
+        world.insert_resource(SurvivalScorePerTick(1));
+        for _ in 0..6 {
+            step(&mut world);
+        }
+        assert_eq!(**world.resource::<TickCount>(), 6);
+        assert_eq!(**world.resource::<Score>(), 6);
+    }
+
+    #[test]
+    fn survival_score_defaults_to_nothing() {
+        let mut world = leftward_snake();
+        for _ in 0..6 {
+            step(&mut world);
+        }
+        assert_eq!(**world.resource::<Score>(), 0);
+    }
+
+    #[test]
+    fn survival_score_skips_the_tick_that_kills() {
+        let mut world = snake_world(
+            &[
+                Position { x: 1, y: 5 },
+                Position { x: 2, y: 5 },
+                Position { x: 3, y: 5 },
+            ],
+            Direction::Left,
+        );
+        world.insert_resource(SurvivalScorePerTick(1));
+        step(&mut world);
+        step(&mut world);
+        assert_eq!(game_overs(&world), 1);
+        assert_eq!(**world.resource::<Score>(), 1);
+    }
+
+    #[test]
+    fn speedrun_only_counts_food() {
+        let mut world = World::new();
+        world.insert_resource(Time::default());
+        world.insert_resource(Events::<GrowthEvent>::default());
+        world.insert_resource(Score(5));
+        world.insert_resource(Speedrun {
+            target: Some(2),
+            ..default()
+        });
+        let mut stage = SystemStage::single_threaded().with_system(speedrun_timer);
+        stage.run(&mut world);
+        assert!(!world.resource::<Speedrun>().running);
+
+        for eaten in 1..=2 {
+            world
+                .resource_mut::<Events<GrowthEvent>>()
+                .send(GrowthEvent);
+            stage.run(&mut world);
+            let speedrun = world.resource::<Speedrun>();
+            assert_eq!(speedrun.points, eaten * FOOD_SCORE);
+            assert_eq!(speedrun.finished, eaten == 2);
+        }
+    }
+
+    #[test]
+    fn reachable_food_never_lands_in_a_walled_pocket() {
+        let mut world = food_world(1);
+        world.insert_resource(ReachableFood(true));
+        let pocket = [
+            Position { x: 0, y: 0 },
+            Position { x: 1, y: 0 },
+            Position { x: 0, y: 1 },
+            Position { x: 1, y: 1 },
+        ];
+        for (x, y) in [(2, 0), (2, 1), (2, 2), (1, 2), (0, 2)] {
+            world.spawn().insert(Position { x, y }).insert(Wall);
+        }
+        let mut stage = SystemStage::single_threaded().with_system(food_spawner);
+        for _ in 0..300 {
+            world.resource_mut::<Events<FoodEvent>>().send(FoodEvent);
+            stage.run(&mut world);
+            let food = world
+                .query_filtered::<(Entity, &Position), With<Food>>()
+                .iter(&world)
+                .map(|(ent, pos)| (ent, *pos))
+                .collect::<Vec<_>>();
+            assert_eq!(food.len(), 1);
+            assert!(!pocket.contains(&food[0].1));
+            world.despawn(food[0].0);
+        }
+        assert_eq!(game_overs(&world), 0);
+    }
+
+    #[test]
+    fn only_unreachable_cells_left_ends_the_round() {
+        let mut world = food_world(1);
+        world.insert_resource(ReachableFood(true));
+        world.insert_resource(ArenaSize {
+            width: 5,
+            height: 1,
+        });
+        for (ent, x) in world
+            .resource::<SnakeSegments>()
+            .0
+            .clone()
+            .into_iter()
+            .zip(0..)
+        {
+            *world.get_mut::<Position>(ent).unwrap() = Position { x, y: 0 };
+        }
+        world.spawn().insert(Position { x: 3, y: 0 }).insert(Wall);
+        world.resource_mut::<Events<FoodEvent>>().send(FoodEvent);
+        spawn_food_frame(&mut world);
+        assert!(food_cells(&mut world).is_empty());
+        assert_eq!(game_overs(&world), 1);
+    }
+
+    fn food_entities(world: &mut World) -> Vec<Entity> {
+        world
+            .query_filtered::<Entity, With<Food>>()
+            .iter(world)
+            .collect()
+    }
+
+    #[test]
+    fn expired_food_is_placed_again() {
+        let mut world = food_world(1);
+        let time = frame_time(Duration::from_millis(10));
+        let lifetime = time.delta().as_secs_f32() * 1.5;
+        world.insert_resource(time);
+        world.insert_resource(GameSpeed::default());
+        world.insert_resource(FoodTimeout(Some(lifetime)));
+        world.resource_mut::<Events<FoodEvent>>().send(FoodEvent);
+        spawn_food_frame(&mut world);
+        let first = food_entities(&mut world);
+        assert_eq!(first.len(), 1);
+
+        let mut expire = SystemStage::single_threaded().with_system(food_lifetime);
+        let mut spawner = SystemStage::single_threaded().with_system(food_spawner);
+        expire.run(&mut world);
+        spawner.run(&mut world);
+        assert_eq!(food_entities(&mut world), first);
+
+        expire.run(&mut world);
+        spawner.run(&mut world);
+        let food = world
+            .query_filtered::<(Entity, &Position, &FoodLifetime), With<Food>>()
+            .iter(&world)
+            .collect::<Vec<_>>();
+        assert_eq!(food.len(), 1);
+        let (ent, pos, lifetime) = food[0];
+        assert_ne!(ent, first[0]);
+        assert!(!snake_cells(&world).contains(pos));
+        // The replacement starts its own lifetime from scratch.
+        assert_eq!(lifetime.0.elapsed(), Duration::ZERO);
+    }
+
+    #[test]
+    fn initial_food_is_used_once() {
+        let mut world = food_world(1);
+        let cell = Position { x: 3, y: 3 };
+        world.insert_resource(InitialFood(Some(cell)));
+        world.resource_mut::<Events<FoodEvent>>().send(FoodEvent);
+        let mut stage = SystemStage::single_threaded().with_system(food_spawner);
+        stage.run(&mut world);
+        assert_eq!(food_cells(&mut world), [cell]);
+        assert_eq!(world.resource::<InitialFood>().0, None);
+
+        for ent in food_entities(&mut world) {
+            world.despawn(ent);
+        }
+        world.resource_mut::<Events<FoodEvent>>().send(FoodEvent);
+        stage.run(&mut world);
+        assert_eq!(food_cells(&mut world).len(), 1);
+    }
+
+    #[test]
+    fn initial_food_on_the_snake_goes_elsewhere() {
+        let mut world = food_world(1);
+        let neck = Position { x: 11, y: 5 };
+        world.insert_resource(InitialFood(Some(neck)));
+        world.resource_mut::<Events<FoodEvent>>().send(FoodEvent);
+        spawn_food_frame(&mut world);
+        let food = food_cells(&mut world);
+        assert_eq!(food.len(), 1);
+        assert!(!snake_cells(&world).contains(&food[0]));
+        assert_eq!(world.resource::<InitialFood>().0, None);
+    }
+
+    #[test]
+    fn coalesced_inputs_take_the_last_turn_of_the_tick() {
+        let mut world = snake_world(
+            &[
+                Position { x: 10, y: 5 },
+                Position { x: 10, y: 6 },
+                Position { x: 10, y: 7 },
+            ],
+            Direction::Down,
+        );
+        world.insert_resource(CoalesceInputs(true));
+        // Up is a reversal here, so Right is all that's left of the tick.
+        press(&mut world, KeyCode::Up);
+        press(&mut world, KeyCode::Right);
+        step(&mut world);
+        assert_eq!(
+            head(&mut world),
+            (Position { x: 11, y: 5 }, Direction::Right)
+        );
+    }
+
+    #[test]
+    fn coalesced_inputs_ending_on_the_heading_drop_the_turn() {
+        let mut world = snake_world(
+            &[
+                Position { x: 10, y: 5 },
+                Position { x: 9, y: 5 },
+                Position { x: 8, y: 5 },
+            ],
+            Direction::Right,
+        );
+        world.insert_resource(CoalesceInputs(true));
+        press(&mut world, KeyCode::Up);
+        press(&mut world, KeyCode::Right);
+        step(&mut world);
+        step(&mut world);
+        assert_eq!(
+            head(&mut world),
+            (Position { x: 12, y: 5 }, Direction::Right)
+        );
+    }
+
+    fn windows_of(width: u32, height: u32, scale_factor: f64) -> Windows {
+        let mut windows = Windows::default();
+        windows.add(Window::new(
+            WindowId::primary(),
+            &WindowDescriptor::default(),
+            width,
+            height,
+            scale_factor,
+            None,
+            RawWindowHandle::Web(WebHandle::empty()),
+        ));
+        windows
+    }
+
+    #[test]
+    fn a_minimized_window_leaves_transforms_alone() {
+        let (mut world, _) = camera_world(CameraMode::ArenaCenter, Position { x: 3, y: 3 });
+        world.insert_resource(CellPadding::default());
+        world.insert_resource(windows_of(0, 0, 1.0));
+        let before = Transform::from_xyz(5.0, 6.0, 0.0);
+        let sprite = world
+            .spawn()
+            .insert(Position { x: 4, y: 4 })
+            .insert(Size::square(0.8))
+            .insert(before)
+            .id();
+        let mut stage = SystemStage::single_threaded()
+            .with_system(position_translation)
+            .with_system(size_scaling);
+        stage.run(&mut world);
+        assert_eq!(*world.get::<Transform>(sprite).unwrap(), before);
+
+        // Once restored, the next frame lays everything out again.
+        world.insert_resource(test_windows());
+        stage.run(&mut world);
+        let transform = world.get::<Transform>(sprite).unwrap();
+        assert!(transform.translation.is_finite() && transform.scale.is_finite());
+        assert_ne!(*transform, before);
+    }
+
+    #[test]
+    fn degenerate_windows_never_write_nan() {
+        let (w, h) = (WINDOW_WIDTH as u32, WINDOW_HEIGHT as u32);
+        // Zero width, a sub-pixel logical size, and a NaN scale factor.
+        for windows in [
+            windows_of(0, h, 1.0),
+            windows_of(w, h, 1e4),
+            windows_of(w, h, f64::NAN),
+        ] {
+            let (mut world, _) = camera_world(CameraMode::ArenaCenter, Position { x: 3, y: 3 });
+            world.insert_resource(CellPadding::default());
+            world.insert_resource(windows);
+            let sprite = world
+                .spawn()
+                .insert(Position { x: 4, y: 4 })
+                .insert(Size::square(0.8))
+                .insert(Transform::identity())
+                .id();
+            SystemStage::single_threaded()
+                .with_system(position_translation)
+                .with_system(size_scaling)
+                .run(&mut world);
+            assert_eq!(
+                *world.get::<Transform>(sprite).unwrap(),
+                Transform::identity()
+            );
+        }
+    }
+
+    #[test]
+    fn a_lone_head_may_reverse() {
+        let mut world = snake_world(&[Position { x: 10, y: 5 }], Direction::Left);
+        world.insert_resource(LoneHeadReverse(true));
+        press(&mut world, KeyCode::Right);
+        step(&mut world);
+        assert_eq!(
+            head(&mut world),
+            (Position { x: 11, y: 5 }, Direction::Right)
+        );
+        assert_eq!(game_overs(&world), 0);
+    }
+
+    #[test]
+    fn a_head_with_a_neck_may_not_reverse() {
+        let mut world = snake_world(
+            &[Position { x: 10, y: 5 }, Position { x: 11, y: 5 }],
+            Direction::Left,
+        );
+        world.insert_resource(LoneHeadReverse(true));
+        press(&mut world, KeyCode::Right);
+        step(&mut world);
+        assert_eq!(head(&mut world), (Position { x: 9, y: 5 }, Direction::Left));
+        assert_eq!(game_overs(&world), 0);
+    }
+
+    #[test]
+    fn lone_head_reversal_passes_the_input_filter() {
+        // Queued back to back, each reversal is checked against the one before it.
+        let mut world = snake_world(&[Position { x: 10, y: 5 }], Direction::Left);
+        world.insert_resource(LoneHeadReverse(true));
+        for key in [KeyCode::Right, KeyCode::Left, KeyCode::Right] {
+            press(&mut world, key);
+        }
+        let mut path = Vec::new();
+        for _ in 0..3 {
+            step(&mut world);
+            path.push(head(&mut world));
+        }
+        assert_eq!(
+            path,
+            [
+                (Position { x: 11, y: 5 }, Direction::Right),
+                (Position { x: 10, y: 5 }, Direction::Left),
+                (Position { x: 11, y: 5 }, Direction::Right),
+            ]
+        );
+
+        // Immediate mode turns right away, but still not once there's a neck.
+        world.insert_resource(BufferMode::Immediate);
+        press(&mut world, KeyCode::Left);
+        assert_eq!(head(&mut world).1, Direction::Left);
+        let mut world = snake_world(
+            &[Position { x: 10, y: 5 }, Position { x: 11, y: 5 }],
+            Direction::Left,
+        );
+        world.insert_resource(LoneHeadReverse(true));
+        world.insert_resource(BufferMode::Immediate);
+        press(&mut world, KeyCode::Right);
+        assert_eq!(head(&mut world).1, Direction::Left);
+    }
+
+    #[test]
+    fn food_waits_for_the_grace_period() {
+        let mut world = food_world(2);
+        world.insert_resource(FoodAfterGrace(true));
+        world.insert_resource(StartGrace(2));
+        for _ in 0..2 {
+            world.resource_mut::<Events<FoodEvent>>().send(FoodEvent);
+        }
+        let mut food = SystemStage::single_threaded()
+            .with_system(release_food)
+            .with_system(food_spawner.after(release_food));
+        food.run(&mut world);
+        assert!(food_cells(&mut world).is_empty());
+
+        step(&mut world);
+        food.run(&mut world);
+        assert!(food_cells(&mut world).is_empty());
+
+        // The last grace tick lets the whole round's food out at once.
+        step(&mut world);
+        food.run(&mut world);
+        assert_eq!(**world.resource::<StartGrace>(), 0);
+        assert_eq!(food_cells(&mut world).len(), 2);
+    }
+
+    #[test]
+    fn padding_shrinks_snake_and_food_but_not_walls() {
+        let mut world = World::new();
+        world.insert_resource(test_windows());
+        world.insert_resource(ArenaSize::default());
+        world.insert_resource(CellPadding(Some(0.25)));
+        let sprites = [ZLayer::Head, ZLayer::Body, ZLayer::Food, ZLayer::Wall].map(|layer| {
+            world
+                .spawn()
+                .insert(layer)
+                .insert(Size::square(0.8))
+                .insert(Transform::default())
+                .id()
+        });
+        SystemStage::single_threaded()
+            .with_system(size_scaling)
+            .run(&mut world);
+        let tile = WINDOW_WIDTH / ARENA_WIDTH as f32;
+        let widths = sprites.map(|ent| world.get::<Transform>(ent).unwrap().scale.x / tile);
+        for (width, expected) in widths.into_iter().zip([0.75, 0.75, 0.75, 0.8]) {
+            assert!((width - expected).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn only_the_first_food_is_kept_near_the_head() {
+        let mut world = food_world(1);
+        world.insert_resource(FirstFoodRadius(Some(2)));
+        let head = Position { x: 10, y: 5 };
+        let distance = |pos: Position| (pos.x - head.x).abs() + (pos.y - head.y).abs();
+        let mut stage = SystemStage::single_threaded().with_system(food_spawner);
+        let mut distances = Vec::new();
+        for _ in 0..50 {
+            world.resource_mut::<Events<FoodEvent>>().send(FoodEvent);
+            stage.run(&mut world);
+            let food = food_cells(&mut world);
+            assert_eq!(food.len(), 1);
+            distances.push(distance(food[0]));
+            for ent in food_entities(&mut world) {
+                world.despawn(ent);
+            }
+        }
+        assert!(distances[0] <= 2);
+        assert!(!world.resource::<FirstFood>().0);
+        assert!(distances[1..].iter().any(|distance| *distance > 2));
+    }
+
+    fn queued_turns(world: &mut World) -> Vec<Direction> {
+        world
+            .query::<&InputBuffer>()
+            .iter(world)
+            .flat_map(|buffer| buffer.inputs.iter().copied())
+            .collect()
+    }
+
+    #[test]
+    fn a_key_held_across_a_restart_needs_a_fresh_press() {
+        let mut world = food_world(1);
+        press(&mut world, KeyCode::Down);
+        press(&mut world, KeyCode::Right);
+        assert_eq!(
+            queued_turns(&mut world),
+            [Direction::Down, Direction::Right]
+        );
+        world
+            .resource_mut::<Events<GameOverEvent>>()
+            .send(GameOverEvent);
+        SystemStage::single_threaded()
+            .with_system(game_over)
+            .run(&mut world);
+        assert!(queued_turns(&mut world).is_empty());
+
+        // Right is still down on the new round's frames, but it was pressed in the last one.
+        let mut input = SystemStage::single_threaded().with_system(snake_movement_input);
+        world.resource_mut::<Input<KeyCode>>().clear();
+        for _ in 0..3 {
+            input.run(&mut world);
+        }
+        assert!(world.resource::<Input<KeyCode>>().pressed(KeyCode::Right));
+        assert!(queued_turns(&mut world).is_empty());
+
+        let mut keyboard_input = world.resource_mut::<Input<KeyCode>>();
+        keyboard_input.release(KeyCode::Right);
+        keyboard_input.clear();
+        keyboard_input.press(KeyCode::Right);
+        input.run(&mut world);
+        assert_eq!(queued_turns(&mut world), [Direction::Right]);
+    }
+
+    // frame_limiter only makes frames longer; the movement timer runs on GameClock, so a
+    // capped run takes as many steps as an uncapped one covering the same time.
+    #[test]
+    fn movement_steps_depend_on_elapsed_time_not_frame_count() {
+        let steps = |max_fps: Option<f32>, frames: &[u64]| {
+            let mut world = timed_snake(Time::default(), 0.01);
+            world.insert_resource(MaxCatchupSteps(10));
+            world.insert_resource(MaxFps(max_fps));
+            let mut stage = SystemStage::single_threaded()
+                .with_system(frame_limiter)
+                .with_system_set(
+                    SystemSet::new()
+                        .with_run_criteria(movement_step)
+                        .with_system(snake_movement),
+                );
+            for ms in frames {
+                world.resource_mut::<GameClock>().delta = Duration::from_millis(*ms);
+                stage.run(&mut world);
+            }
+            let ticks = **world.resource::<TickCount>();
+            ticks
+        };
+        let uncapped = steps(None, &[8; 8]);
+        assert_eq!(uncapped, 6);
+        assert_eq!(steps(Some(30.0), &[32, 32]), uncapped);
+    }
+
+    #[test]
+    fn hidden_segments_still_collide() {
+        let mut world = snake_world(
+            &[
+                Position { x: 10, y: 5 },
+                Position { x: 10, y: 4 },
+                Position { x: 11, y: 4 },
+                Position { x: 11, y: 5 },
+                Position { x: 12, y: 5 },
+            ],
+            Direction::Up,
+        );
+        world.insert_resource(RenderEvery(2));
+        let segments = world.resource::<SnakeSegments>().0.clone();
+        for ent in &segments {
+            world.entity_mut(*ent).insert(Visibility::default());
+        }
+        SystemStage::single_threaded()
+            .with_system(segment_spacing)
+            .run(&mut world);
+        let visible = segments
+            .iter()
+            .map(|ent| world.get::<Visibility>(*ent).unwrap().is_visible)
+            .collect::<Vec<_>>();
+        assert_eq!(visible, [true, false, true, false, true]);
+
+        // Turning right runs into the hidden fourth segment.
+        press(&mut world, KeyCode::Right);
+        step(&mut world);
+        assert_eq!(game_overs(&world), 1);
+    }
+
+    // Four cells in a row with a head at each end: the front one heading left, the back one
+    // right.
+    fn two_headed_snake() -> World {
+        let mut world = snake_world(
+            &[
+                Position { x: 10, y: 5 },
+                Position { x: 11, y: 5 },
+                Position { x: 12, y: 5 },
+                Position { x: 13, y: 5 },
+            ],
+            Direction::Left,
+        );
+        world.insert_resource(TwoHeaded(true));
+        let back = *world.resource::<SnakeSegments>().last().unwrap();
+        world.entity_mut(back).insert(SnakeHead {
+            direction: Direction::Right,
+        });
+        world
+    }
+
+    fn head_cells(world: &mut World) -> Vec<(Position, Direction)> {
+        let mut cells = world
+            .query::<(&Position, &SnakeHead)>()
+            .iter(world)
+            .map(|(pos, head)| (*pos, head.direction))
+            .collect::<Vec<_>>();
+        cells.sort_by_key(|(pos, _)| pos.x);
+        cells
+    }
+
+    #[test]
+    fn two_heads_take_turns() {
+        let mut world = two_headed_snake();
+        step(&mut world);
+        assert_eq!(
+            head_cells(&mut world),
+            [
+                (Position { x: 11, y: 5 }, Direction::Left),
+                (Position { x: 14, y: 5 }, Direction::Right),
+            ]
+        );
+        step(&mut world);
+        assert_eq!(
+            head_cells(&mut world),
+            [
+                (Position { x: 10, y: 5 }, Direction::Left),
+                (Position { x: 13, y: 5 }, Direction::Right),
+            ]
+        );
+        assert_eq!(game_overs(&world), 0);
+    }
+
+    #[test]
+    fn turns_go_to_the_head_that_moves_next() {
+        let mut world = two_headed_snake();
+        press(&mut world, KeyCode::Up);
+        step(&mut world);
+        assert_eq!(
+            head_cells(&mut world),
+            [
+                (Position { x: 11, y: 5 }, Direction::Left),
+                (Position { x: 13, y: 6 }, Direction::Up),
+            ]
+        );
+    }
+
+    #[test]
+    fn either_head_dies_on_the_other() {
+        let mut world = snake_world(
+            &[
+                Position { x: 10, y: 5 },
+                Position { x: 10, y: 4 },
+                Position { x: 11, y: 4 },
+                Position { x: 11, y: 5 },
+            ],
+            Direction::Up,
+        );
+        world.insert_resource(TwoHeaded(true));
+        let back = *world.resource::<SnakeSegments>().last().unwrap();
+        world.entity_mut(back).insert(SnakeHead {
+            direction: Direction::Up,
+        });
+        // The back head moves first and turns straight into the front one.
+        press(&mut world, KeyCode::Left);
+        step(&mut world);
+        assert_eq!(game_overs(&world), 1);
+    }
+
+    #[test]
+    fn two_headed_growth_goes_in_the_middle() {
+        let mut world = two_headed_snake();
+        insert_look(&mut world);
+        world.insert_resource(TwoHeaded(true));
+        world.insert_resource(GrowthAnchor::Tail);
+        world.insert_resource(Milestones::default());
+        world.insert_resource(Events::<GrowthEvent>::default());
+        world.insert_resource(Events::<MilestoneEvent>::default());
+        step(&mut world);
+        let before = world.resource::<SnakeSegments>().0.clone();
+        world
+            .resource_mut::<Events<GrowthEvent>>()
+            .send(GrowthEvent);
+        SystemStage::single_threaded()
+            .with_system(snake_growth)
+            .run(&mut world);
+        let segments = world.resource::<SnakeSegments>().0.clone();
+        assert_eq!(segments[..2], before[..2]);
+        assert!(!before.contains(&segments[2]));
+        assert_eq!(segments[3..], before[2..]);
+        // The head that stood still this tick is back on its own cell.
+        assert_eq!(
+            snake_cells(&world),
+            (10..=14)
+                .rev()
+                .map(|x| Position { x, y: 5 })
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn a_two_headed_round_starts_with_both_heads() {
+        let mut world = food_world(1);
+        for ent in world.resource::<SnakeSegments>().0.clone() {
+            world.despawn(ent);
+        }
+        world.insert_resource(TwoHeaded(true));
+        SystemStage::single_threaded()
+            .with_system(setup_snake_game)
+            .run(&mut world);
+        let start = BorderWall::default().start();
+        assert_eq!(snake_cells(&world), start);
+        let mut heads = head_cells(&mut world);
+        heads.sort_by_key(|(pos, _)| pos.y);
+        assert_eq!(
+            heads,
+            [(start[1], Direction::Down), (start[0], Direction::Up)]
+        );
+    }
+
+    #[test]
+    fn rewinding_brings_food_back_with_its_kind() {
+        let mut world = leftward_snake();
+        insert_look(&mut world);
+        world.insert_resource(FoodTimeout(None));
+        world.insert_resource(State::new(GameState::Playing));
+        world
+            .spawn()
+            .insert(Food)
+            .insert(Position { x: 3, y: 3 })
+            .insert(FreezeFood);
+        world
+            .spawn()
+            .insert(Food)
+            .insert(Position { x: 4, y: 4 })
+            .insert(SmartFood { moves_left: 7 });
+        world.spawn().insert(Food).insert(Position { x: 5, y: 5 });
+        step(&mut world);
+
+        let mut keyboard_input = Input::<KeyCode>::default();
+        keyboard_input.press(REWIND_KEY);
+        world.insert_resource(keyboard_input);
+        SystemStage::single_threaded()
+            .with_system(snake_rewind)
+            .run(&mut world);
+
+        let mut food = world
+            .query_filtered::<(&Position, Option<&FreezeFood>, Option<&SmartFood>), With<Food>>()
+            .iter(&world)
+            .map(|(pos, freeze, smart)| SavedFood::new(*pos, freeze, smart))
+            .collect::<Vec<_>>();
+        food.sort_by_key(|food| food.position.x);
+        assert_eq!(
+            food.iter().map(|food| food.kind).collect::<Vec<_>>(),
+            [
+                FoodKind::Freeze,
+                FoodKind::Smart { moves_left: 7 },
+                FoodKind::Plain,
+            ]
+        );
+    }
+
+    #[test]
+    fn saves_from_before_food_kinds_load_as_plain_food() {
+        let json = r#"{"segments":[{"x":1,"y":1}],"direction":"Up","food":[{"x":2,"y":2}]}"#;
+        let snapshot = serde_json::from_str::<GameSnapshot>(json).unwrap();
+        assert_eq!(
+            snapshot.food,
+            [SavedFood {
+                position: Position { x: 2, y: 2 },
+                kind: FoodKind::Plain,
+            }]
+        );
+
+        let smart = SavedFood {
+            position: Position { x: 2, y: 2 },
+            kind: FoodKind::Smart { moves_left: 3 },
+        };
+        let json = serde_json::to_string(&smart).unwrap();
+        assert_eq!(serde_json::from_str::<SavedFood>(&json).unwrap(), smart);
+    }
+
+    fn saved_round(config: Option<SaveConfig>) -> SaveGame {
+        SaveGame {
+            arena_width: ARENA_WIDTH,
+            arena_height: ARENA_HEIGHT,
+            snapshot: GameSnapshot {
+                segments: vec![Position { x: 4, y: 4 }, Position { x: 4, y: 3 }],
+                direction: Direction::Up,
+                food: vec![],
+                walls: vec![],
+            },
+            score: 3,
+            teleport_charges: 0,
+            config,
+        }
+    }
+
+    #[test]
+    fn saves_are_checked_against_the_live_arena() {
+        let arena = ArenaSize::default();
+        let border = BorderWall::default();
+        assert!(saved_round(None).validate(&arena, &border).is_ok());
+
+        let mut grown = saved_round(None);
+        grown.arena_width += 2;
+        assert!(grown.validate(&arena, &border).is_err());
+        let bigger = ArenaSize {
+            width: arena.width + 2,
+            ..arena
+        };
+        assert!(grown.validate(&bigger, &border).is_ok());
+    }
+
+    #[test]
+    fn resuming_a_session_restores_its_settings_but_not_the_arena() {
+        let mut world = food_world(1);
+        let config = SaveConfig {
+            food_bias: FoodBias::AvoidAhead,
+            food_count: 3,
+            tick_score: 2,
+            walls_per_food: 1,
+            border: 1,
+            game_speed: 0.5,
+            step_secs: 0.2,
+            food_seed: Some(SeedState {
+                seed: 3,
+                word_pos: 40,
+            }),
+        };
+        let mut save = saved_round(Some(config));
+        save.arena_width -= 2;
+        world.insert_resource(PendingSession(Some(save)));
+        world.insert_resource(State::new(GameState::Playing));
+        world.insert_resource(Score::default());
+        world.insert_resource(RewindHistory::default());
+        world.insert_resource(SurvivalScorePerTick::default());
+        world.insert_resource(WallsPerFood::default());
+        world.insert_resource(GameSpeed::default());
+        let mut keyboard_input = Input::<KeyCode>::default();
+        keyboard_input.press(RESUME_KEY);
+        world.insert_resource(keyboard_input);
+        SystemStage::single_threaded()
+            .with_system(load_state)
+            .run(&mut world);
+
+        assert_eq!(*world.resource::<ArenaSize>(), ArenaSize::default());
+        let restored = SaveConfig {
+            food_bias: *world.resource::<FoodBias>(),
+            food_count: **world.resource::<FoodCount>(),
+            tick_score: world.resource::<SurvivalScorePerTick>().0,
+            walls_per_food: world.resource::<WallsPerFood>().0,
+            border: world.resource::<BorderWall>().thickness,
+            game_speed: world.resource::<GameSpeed>().0,
+            step_secs: world
+                .resource::<MovementTimer>()
+                .timer
+                .duration()
+                .as_secs_f32(),
+            food_seed: world.resource::<FoodRandom>().0.seed_state(),
+        };
+        assert_eq!(restored, config);
+        assert!(world
+            .query_filtered::<&Position, With<BorderTile>>()
+            .iter(&world)
+            .any(|pos| *pos == Position { x: 0, y: 0 }));
+        assert_eq!(**world.resource::<Score>(), 3);
+    }
+
+    #[test]
+    fn a_resumed_seed_carries_on_where_it_left_off() {
+        let free = (0..10).map(|x| Position { x, y: 0 }).collect::<Vec<_>>();
+        let mut original = SeededRandom::new(7);
+        original.roll();
+        original.next_free_cell(&free);
+        let mut resumed = SeededRandom::resume(original.seed_state().unwrap());
+        for _ in 0..20 {
+            assert_eq!(original.roll(), resumed.roll());
+            assert_eq!(
+                original.next_free_cell(&free),
+                resumed.next_free_cell(&free)
+            );
+        }
+        assert_eq!(ThreadRandom.seed_state(), None);
+    }
+
+    #[test]
+    fn high_contrast_outlines_food_and_walls() {
+        for (theme, outlined) in [(Theme::default(), false), (Theme::high_contrast(), true)] {
+            let mut world = World::new();
+            world.insert_resource(theme);
+            let food = world.spawn().insert(ZLayer::Food).id();
+            let wall = world.spawn().insert(ZLayer::Wall).id();
+            let ice = world.spawn().insert(ZLayer::Background).id();
+            SystemStage::single_threaded()
+                .with_system(outline_board)
+                .run(&mut world);
+            for ent in [food, wall] {
+                assert_eq!(world.get::<Children>(ent).is_some(), outlined);
+            }
+            assert!(world.get::<Children>(ice).is_none());
+        }
+    }
+
+    #[test]
+    fn game_over_resets_the_round() {
+        let mut world = food_world(1);
+        world.insert_resource(ReverseCharges(0));
+        world.insert_resource(GraceTicks(3));
+        world.insert_resource(StartGrace(0));
+        world.insert_resource(FirstFood(false));
+        world.insert_resource(HeatmapPerRun(true));
+        world.resource_mut::<Heatmap>()[0] = 5;
+        let mut hunger = Timer::from_seconds(2.0, true);
+        hunger.tick(Duration::from_secs(1));
+        world.insert_resource(Hunger(Some(hunger)));
+        world.insert_resource(ArenaSize {
+            width: ARENA_WIDTH + 2,
+            height: ARENA_HEIGHT + 2,
+        });
+        world.insert_resource(PendingSession(Some(saved_round(None))));
+        let session = world.resource::<SessionFile>().0.clone();
+        saved_round(None).write(&session).unwrap();
+        world
+            .resource_mut::<Events<GameOverEvent>>()
+            .send(GameOverEvent);
+        SystemStage::single_threaded()
+            .with_system(game_over)
+            .run(&mut world);
+        assert_eq!(**world.resource::<ReverseCharges>(), REVERSE_CHARGES);
+        assert_eq!(**world.resource::<StartGrace>(), 3);
+        assert!(world.resource::<FirstFood>().0);
+        assert!(world
+            .resource::<Heatmap>()
+            .iter()
+            .all(|visits| *visits == 0));
+        assert_eq!(
+            world
+                .resource::<Hunger>()
+                .0
+                .as_ref()
+                .unwrap()
+                .elapsed_secs(),
+            0.0
+        );
+        assert_eq!(*world.resource::<ArenaSize>(), ArenaSize::default());
+        assert!(world.resource::<PendingSession>().0.is_none());
+        assert!(!std::path::Path::new(&session).exists());
+    }
+
+    #[test]
+    fn nothing_may_start_under_the_border() {
+        let arena = ArenaSize::default();
+        let border = BorderWall { thickness: 2 };
+        let mut snapshot = saved_round(None).snapshot;
+        assert!(snapshot.validate_scenario(&arena, &border).is_ok());
+        snapshot.food.push(SavedFood {
+            position: Position { x: 1, y: 8 },
+            kind: FoodKind::Plain,
+        });
+        assert!(snapshot.validate_scenario(&arena, &border).is_err());
+        assert!(snapshot
+            .validate_scenario(&arena, &BorderWall::default())
+            .is_ok());
+
+        // A save is checked against the border it brings along, not the live one.
+        let mut save = saved_round(None);
+        save.snapshot.segments = vec![Position { x: 1, y: 1 }, Position { x: 1, y: 2 }];
+        assert!(save.validate(&arena, &border).is_err());
+        save.config = Some(SaveConfig {
+            food_bias: FoodBias::None,
+            food_count: 1,
+            tick_score: 0,
+            walls_per_food: 0,
+            border: 0,
+            game_speed: 1.0,
+            step_secs: MOVEMENT_STEP_SECS,
+            food_seed: None,
+        });
+        assert!(save.validate(&arena, &border).is_ok());
+        save.config.as_mut().unwrap().border = 2;
+        assert!(save.validate(&arena, &BorderWall::default()).is_err());
+    }
 }